@@ -1,7 +1,8 @@
 //!  Procedure macros for <https://crates.io/crates/bma-benchmark>
-use proc_macro::{TokenStream, TokenTree};
-use quote::{quote, ToTokens};
-use std::panic::panic_any;
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2, TokenTree};
+use quote::{quote, quote_spanned, ToTokens};
+use syn::spanned::Spanned;
 
 const ERR_INVALID_OPTIONS: &str = "Invalid options";
 
@@ -10,9 +11,13 @@ const ERR_INVALID_OPTIONS: &str = "Invalid options";
 ///
 /// Attribute options:
 ///
-/// * **i** number of iterations, required
-/// * **name** custom stage name (the default is function name)
+/// * **i** number of iterations, required, either an integer literal or an integer constant (or
+///   a path to it), the constant is converted to u64 at run time (panics if the value does not
+///   fit)
+/// * **name** custom stage name (the default is function name), either an identifier or a
+///   string literal (which may contain spaces and escape sequences)
 /// * **check** check for the result, the function body MUST (not return but) END with a bool
+/// * **warmup** warm up CPU for the specified number of seconds before the stage (0 = no warmup)
 ///
 /// If a function name starts with *test_* or *benchmark_*, the prefix is automatically stripped.
 ///
@@ -33,71 +38,142 @@ const ERR_INVALID_OPTIONS: &str = "Invalid options";
 /// ```
 ///
 /// ```rust
+/// #[benchmark_stage(i=1_000,name=stage1,warmup=3)]
+/// fn test1() {
+///     // do something
+/// }
+/// ```
+///
+/// ```rust
+/// const ITERS: u32 = 1_000;
+///
+/// #[benchmark_stage(i=ITERS)]
+/// fn test1() {
+///     // do something
+/// }
+/// ```
+///
+/// ```rust
 /// #[benchmark_stage(i=1_000,name=stage1,check)]
 /// fn test1() {
 ///     File::create("/tmp/test123").is_ok()
 /// }
 /// ```
 ///
-/// # Panics
-///
-/// Will panic on invalid options
 pub fn benchmark_stage(args: TokenStream, input: TokenStream) -> TokenStream {
-    let mut item: syn::Item = syn::parse(input).expect("Invalid input");
-    let mut args_iter = args.into_iter();
-    let mut opt_i: Option<u32> = None;
+    match expand_benchmark_stage(args.into(), input.into()) {
+        Ok(v) => v.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn parse_int(v: &TokenTree, opt: &str) -> syn::Result<u64> {
+    if let TokenTree::Literal(lit) = v {
+        if let Ok(i) = lit.to_string().replace('_', "").parse() {
+            return Ok(i);
+        }
+    }
+    Err(syn::Error::new_spanned(
+        v,
+        format!("Invalid value for \"{}\", integer expected", opt),
+    ))
+}
+
+fn expand_benchmark_stage(args: TokenStream2, input: TokenStream2) -> syn::Result<TokenStream2> {
+    let mut item: syn::Item = syn::parse2(input)?;
+    let mut args_iter = args.into_iter().peekable();
+    let mut opt_i: Option<TokenStream2> = None;
     let mut opt_name: Option<String> = None;
+    let mut opt_warmup: u64 = 0;
     let mut checked = false;
     macro_rules! parse_opt {
-        ($c: block) => {{
-            let v = args_iter.next().expect(ERR_INVALID_OPTIONS);
-            if let TokenTree::Punct(c) = v {
-                if c.as_char() == '=' {
-                    $c
-                } else {
-                    panic_any(ERR_INVALID_OPTIONS);
-                }
-            } else {
-                panic_any(ERR_INVALID_OPTIONS);
+        ($ident: expr, $v: ident, $c: block) => {{
+            match args_iter.next() {
+                Some(TokenTree::Punct(c)) if c.as_char() == '=' => {}
+                Some(v) => return Err(syn::Error::new_spanned(v, ERR_INVALID_OPTIONS)),
+                None => return Err(syn::Error::new_spanned($ident, ERR_INVALID_OPTIONS)),
             }
+            let $v = args_iter
+                .next()
+                .ok_or_else(|| syn::Error::new_spanned($ident, "Option value not specified"))?;
+            $c
         }};
     }
     while let Some(v) = args_iter.next() {
-        if let TokenTree::Ident(i) = v {
+        if let TokenTree::Ident(ref i) = v {
             let s = i.to_string();
             match s.as_str() {
-                "i" => parse_opt!({
-                    if let TokenTree::Literal(v) =
-                        args_iter.next().expect("Option value not specified")
-                    {
-                        opt_i = Some(
-                            v.to_string()
-                                .replace('_', "")
-                                .parse()
-                                .expect("Invalid integer"),
-                        );
+                "i" => parse_opt!(&v, value, {
+                    opt_i = Some(if let TokenTree::Ident(_) = value {
+                        // a constant or a path to it, converted to u64 at run time
+                        let mut tokens = vec![value];
+                        while let Some(t) = args_iter.peek() {
+                            if matches!(t, TokenTree::Punct(c) if c.as_char() == ',') {
+                                break;
+                            }
+                            tokens.push(args_iter.next().unwrap());
+                        }
+                        let path: syn::Path =
+                            syn::parse2(tokens.into_iter().collect()).map_err(|e| {
+                                syn::Error::new(
+                                    e.span(),
+                                    "Invalid value for \"i\", integer or constant expected",
+                                )
+                            })?;
+                        quote_spanned!(path.span()=> ::bma_benchmark::iterations_from(#path))
                     } else {
-                        panic!("Invalid value for \"i\"");
-                    }
+                        let i = parse_int(&value, "i")?;
+                        quote!(#i)
+                    });
                 }),
-                "name" => parse_opt!({
-                    match args_iter.next().unwrap() {
-                        TokenTree::Literal(v) => opt_name = Some(v.to_string()),
+                "name" => parse_opt!(&v, value, {
+                    match value {
+                        TokenTree::Literal(_) => {
+                            let lit: syn::Lit = syn::parse2(value.clone().into())?;
+                            opt_name = Some(match lit {
+                                syn::Lit::Str(s) => s.value(),
+                                syn::Lit::Int(i) => i.base10_digits().to_owned(),
+                                _ => {
+                                    return Err(syn::Error::new_spanned(
+                                        value,
+                                        "Invalid value for \"name\"",
+                                    ))
+                                }
+                            });
+                        }
                         TokenTree::Ident(v) => opt_name = Some(v.to_string()),
-                        _ => panic!("Invalid value for \"name\""),
+                        _ => {
+                            return Err(syn::Error::new_spanned(
+                                value,
+                                "Invalid value for \"name\"",
+                            ))
+                        }
                     }
                 }),
+                "warmup" => parse_opt!(&v, value, {
+                    opt_warmup = parse_int(&value, "warmup")?;
+                }),
                 "check" => checked = true,
-                _ => panic!("Invalid parameter: {}", s),
+                _ => {
+                    return Err(syn::Error::new_spanned(
+                        &v,
+                        format!("Invalid parameter: {}", s),
+                    ))
+                }
             }
         }
     }
-    let iterations = opt_i.expect("Iterations not specified");
+    let iterations = opt_i.ok_or_else(|| {
+        syn::Error::new(
+            Span::call_site(),
+            "Iterations not specified, use e.g. #[benchmark_stage(i=1_000)]",
+        )
+    })?;
     let fn_item = match &mut item {
         syn::Item::Fn(fn_item) => fn_item,
-        _ => panic!("expected fn"),
+        _ => return Err(syn::Error::new_spanned(&item, "expected fn")),
     };
-    let mut name = opt_name.unwrap_or_else(|| {
+    let name = opt_name.unwrap_or_else(|| {
         let n = fn_item.sig.ident.to_string();
         if n.starts_with("test_") {
             n.strip_prefix("test_").unwrap().to_owned()
@@ -107,20 +183,29 @@ pub fn benchmark_stage(args: TokenStream, input: TokenStream) -> TokenStream {
             n
         }
     });
-    if name.starts_with('"') && name.ends_with('"') {
-        name = name[1..name.len() - 1].to_owned();
-    }
     let fn_block = &fn_item.block;
+    let mut stmts = Vec::new();
+    if opt_warmup > 0 {
+        stmts.push(
+            syn::parse2(
+                quote!(::bma_benchmark::warmup_for(::std::time::Duration::from_secs(#opt_warmup));),
+            )
+            .unwrap(),
+        );
+    }
     if checked {
-        fn_item.block.stmts = vec![syn::parse(
-            quote!(::bma_benchmark::staged_benchmark_check!(#name, #iterations, #fn_block);).into(),
-        )
-        .unwrap()];
+        stmts.push(
+            syn::parse2(
+                quote!(::bma_benchmark::staged_benchmark_check!(#name, #iterations, #fn_block);),
+            )
+            .unwrap(),
+        );
     } else {
-        fn_item.block.stmts = vec![syn::parse(
-            quote!(::bma_benchmark::staged_benchmark!(#name, #iterations, #fn_block);).into(),
-        )
-        .unwrap()];
+        stmts.push(
+            syn::parse2(quote!(::bma_benchmark::staged_benchmark!(#name, #iterations, #fn_block);))
+                .unwrap(),
+        );
     }
-    item.into_token_stream().into()
+    fn_item.block.stmts = stmts;
+    Ok(item.into_token_stream())
 }
@@ -6,11 +6,13 @@ extern crate prettytable;
 
 pub use bma_benchmark_proc::benchmark_stage;
 use colored::Colorize;
-use num_format::{Locale, ToFormattedString};
+pub use num_format::Locale;
+use num_format::ToFormattedString;
 use prettytable::Table;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::fmt;
-use std::sync::Mutex;
+use std::io::{self, IsTerminal, Write};
+use std::sync::{Mutex, MutexGuard, Once};
 use std::time::Duration;
 use std::time::Instant;
 use terminal_size::{terminal_size, Height, Width};
@@ -19,62 +21,567 @@ lazy_static! {
     pub static ref DEFAULT_BENCHMARK: Mutex<Benchmark> = Mutex::new(Benchmark::new0());
     pub static ref DEFAULT_STAGED_BENCHMARK: Mutex<StagedBenchmark> =
         Mutex::new(StagedBenchmark::new());
+    static ref NAMED_BENCHMARKS: Mutex<BTreeMap<String, Benchmark>> = Mutex::new(BTreeMap::new());
+    static ref OUTPUT_WIDTH: Mutex<Option<u16>> = Mutex::new(None);
+    static ref NUMBER_LOCALE: Mutex<Locale> = Mutex::new(Locale::en);
+    static ref NUMBER_STYLE: Mutex<NumberStyle> = Mutex::new(NumberStyle::default());
+    static ref TABLE_STYLE: Mutex<TableStyle> = Mutex::new(TableStyle::default());
+    static ref ITERATION_SCALE: f64 = iteration_scale_from_env();
+    static ref COLOR_ENABLED: bool = color_enabled_from_env();
+    static ref PROGRESS_ENABLED: bool = std::io::stderr().is_terminal();
+}
+
+/// Lock the default simple benchmark, used by macros
+///
+/// If the mutex is poisoned (a benchmark panicked while holding the lock), the lock is recovered,
+/// so a panic in one benchmark does not cascade. Note that the recovered state may be partial
+pub fn default_benchmark() -> MutexGuard<'static, Benchmark> {
+    DEFAULT_BENCHMARK
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+/// Lock the default staged benchmark, used by macros
+///
+/// If the mutex is poisoned (a benchmark panicked while holding the lock), the lock is recovered,
+/// so a panic in one benchmark does not cascade. Note that the recovered state may be partial
+pub fn default_staged_benchmark() -> MutexGuard<'static, StagedBenchmark> {
+    DEFAULT_STAGED_BENCHMARK
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+/// Lock the named simple benchmark registry, used by macros
+///
+/// The mutex poisoning is recovered the same way as for [`default_benchmark`]
+pub fn named_benchmarks() -> MutexGuard<'static, BTreeMap<String, Benchmark>> {
+    NAMED_BENCHMARKS
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+static COLOR_INIT: Once = Once::new();
+
+// Option::is_none_or requires a newer compiler
+#[allow(clippy::unnecessary_map_or)]
+fn color_enabled_from_env() -> bool {
+    std::env::var_os("NO_COLOR").map_or(true, |v| v.is_empty()) && std::io::stdout().is_terminal()
+}
+
+/// Disables colored output if NO_COLOR is set or stdout is not a terminal
+///
+/// The check is performed once, before the first output
+fn init_color() {
+    COLOR_INIT.call_once(|| {
+        if !*COLOR_ENABLED {
+            colored::control::set_override(false);
+        }
+    });
 }
 
 macro_rules! result_separator {
     () => {
         separator("--- Benchmark results ")
     };
+    ($label: expr) => {
+        match $label {
+            Some(label) => separator(&format!("--- Benchmark results: {} ", label)),
+            None => result_separator!(),
+        }
+    };
 }
 
 macro_rules! format_number {
     ($n: expr) => {
-        $n.to_formatted_string(&Locale::en).replace(',', "_")
+        format_number_styled($n)
+    };
+}
+
+/// Number formatting style for results output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NumberStyle {
+    /// digits grouped with underscores (e.g. 1_000_000)
+    #[default]
+    Underscore,
+    /// digits grouped with the locale separators (e.g. 1,000,000 for the en locale)
+    Comma,
+    /// no digit grouping (e.g. 1000000)
+    Plain,
+}
+
+/// Result table style
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TableStyle {
+    /// space-separated columns with the title underlined
+    #[default]
+    Default,
+    /// box-drawing borders and separators
+    BoxChars,
+    /// no borders and separators
+    Clean,
+    /// outer borders only
+    BordersOnly,
+    /// borders and column separators, no line separators except the title one
+    NoLinesep,
+}
+
+impl TableStyle {
+    fn format(self) -> prettytable::format::TableFormat {
+        match self {
+            TableStyle::Default => prettytable::format::FormatBuilder::new()
+                .column_separator(' ')
+                .borders(' ')
+                .separators(
+                    &[prettytable::format::LinePosition::Title],
+                    prettytable::format::LineSeparator::new('-', '-', '-', '-'),
+                )
+                .padding(0, 1)
+                .build(),
+            TableStyle::BoxChars => *prettytable::format::consts::FORMAT_BOX_CHARS,
+            TableStyle::Clean => *prettytable::format::consts::FORMAT_CLEAN,
+            TableStyle::BordersOnly => *prettytable::format::consts::FORMAT_BORDERS_ONLY,
+            TableStyle::NoLinesep => *prettytable::format::consts::FORMAT_NO_LINESEP_WITH_TITLE,
+        }
+    }
+}
+
+/// Time unit for durations output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimeUnit {
+    /// nanoseconds
+    Ns,
+    /// microseconds
+    #[default]
+    Us,
+    /// milliseconds
+    Ms,
+}
+
+impl TimeUnit {
+    /// Get a duration value in the unit (truncated)
+    pub fn value_of(self, duration: Duration) -> u128 {
+        match self {
+            TimeUnit::Ns => duration.as_nanos(),
+            TimeUnit::Us => duration.as_micros(),
+            TimeUnit::Ms => duration.as_millis(),
+        }
+    }
+
+    /// Get the unit name
+    pub fn name(self) -> &'static str {
+        match self {
+            TimeUnit::Ns => "nanoseconds",
+            TimeUnit::Us => "microseconds",
+            TimeUnit::Ms => "milliseconds",
+        }
+    }
+}
+
+/// Set the locale used to group digits in numbers, the default is [`Locale::en`]
+pub fn set_number_locale(locale: Locale) {
+    *NUMBER_LOCALE
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner) = locale;
+}
+
+/// Set the number formatting style, the default is [`NumberStyle::Underscore`]
+pub fn set_number_style(style: NumberStyle) {
+    *NUMBER_STYLE
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner) = style;
+}
+
+/// Set the result table style, the default is [`TableStyle::Default`]
+pub fn set_table_format(format: TableStyle) {
+    *TABLE_STYLE
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner) = format;
+}
+
+fn format_number_styled<N: ToFormattedString + fmt::Display>(n: N) -> String {
+    let locale = *NUMBER_LOCALE
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    match *NUMBER_STYLE
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+    {
+        NumberStyle::Underscore => n
+            .to_formatted_string(&locale)
+            .replace(locale.separator(), "_"),
+        NumberStyle::Comma => n.to_formatted_string(&locale),
+        NumberStyle::Plain => n.to_string(),
+    }
+}
+
+const ITERATION_SCALE_ENV: &str = "BMA_BENCH_SCALE";
+
+fn iteration_scale_from_env() -> f64 {
+    let Some(value) = std::env::var_os(ITERATION_SCALE_ENV) else {
+        return 1.0;
+    };
+    match value.to_str().and_then(|v| v.trim().parse::<f64>().ok()) {
+        Some(scale) if scale.is_finite() && scale > 0.0 => scale,
+        _ => {
+            eprintln!(
+                "invalid {} value: {:?}, using 1.0",
+                ITERATION_SCALE_ENV, value
+            );
+            1.0
+        }
+    }
+}
+
+#[allow(clippy::cast_sign_loss)]
+#[doc(hidden)]
+/// convert a constant number of iterations to u64, used by benchmark_stage attribute
+///
+/// # Panics
+///
+/// Will panic if the value does not fit into u64
+pub fn iterations_from<T>(iterations: T) -> u64
+where
+    T: TryInto<u64>,
+    T::Error: fmt::Debug,
+{
+    iterations
+        .try_into()
+        .expect("the number of iterations does not fit into u64")
+}
+
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_precision_loss)]
+/// scale the number of iterations by BMA_BENCH_SCALE environment variable (a float, the default
+/// is 1.0)
+///
+/// Used by benchmark, benchmark_check, staged_benchmark and staged_benchmark_check macros. The
+/// variable is read once, invalid values are ignored with a warning. A non-zero number of
+/// iterations is never scaled below 1
+pub fn scale_iterations(iterations: u64) -> u64 {
+    let scale = *ITERATION_SCALE;
+    if scale == 1.0 || iterations == 0 {
+        iterations
+    } else {
+        ((iterations as f64 * scale).round() as u64).max(1)
+    }
+}
+
+#[doc(hidden)]
+#[macro_export]
+/// call the stage closure (untimed) for the specified per-stage warmup duration
+macro_rules! staged_benchmark_warmup {
+    ($warmup: expr, $stage: ident) => {
+        let bma_benchmark_warmup: ::std::time::Duration = $warmup;
+        if !bma_benchmark_warmup.is_zero() {
+            let started = ::std::time::Instant::now();
+            while started.elapsed() < bma_benchmark_warmup {
+                black_box($stage());
+            }
+        }
     };
 }
 
 #[macro_export]
 /// run a stage of staged bechmark
+///
+/// If the stage timeout is set for the default staged benchmark, the stage is stopped after the
+/// timeout is exceeded. If neither timeout, abort nor per-stage warmup is set, the code is run in
+/// a plain loop
 macro_rules! staged_benchmark {
     ($name: expr, $iterations: expr, $code: block) => {
-        $crate::staged_benchmark_start!($name);
-        black_box(move || {
-        for _iteration in 0..$iterations
-            $code
-        })();
-        $crate::staged_benchmark_finish!($name, $iterations);
+        let bma_benchmark_iterations: u64 = $crate::scale_iterations($iterations);
+        let (bma_benchmark_timeout, bma_benchmark_abort, bma_benchmark_warmup) = {
+            let staged_benchmark = $crate::default_staged_benchmark();
+            (
+                staged_benchmark.stage_timeout(),
+                staged_benchmark.abort_check(bma_benchmark_iterations),
+                staged_benchmark.per_stage_warmup(),
+            )
+        };
+        let (bma_benchmark_completed, bma_benchmark_aborted) = if bma_benchmark_timeout.is_none()
+            && bma_benchmark_abort.is_none()
+            && bma_benchmark_warmup.is_zero()
+        {
+            $crate::staged_benchmark_start!($name);
+            black_box(move || {
+                for _iteration in 0..bma_benchmark_iterations
+                    $code
+            })();
+            (bma_benchmark_iterations, false)
+        } else {
+            #[allow(unused_mut)]
+            let mut bma_benchmark_stage = black_box(move || $code);
+            $crate::staged_benchmark_warmup!(bma_benchmark_warmup, bma_benchmark_stage);
+            $crate::staged_benchmark_start!($name);
+            black_box(move || {
+                let started = ::std::time::Instant::now();
+                for _iteration in 0..bma_benchmark_iterations {
+                    black_box(bma_benchmark_stage());
+                    if let Some(timeout) = bma_benchmark_timeout {
+                        if started.elapsed() >= timeout {
+                            return (_iteration + 1, false);
+                        }
+                    }
+                    if let Some((window, min_speed)) = bma_benchmark_abort {
+                        if _iteration + 1 == window
+                            && ((_iteration + 1) as f64 / started.elapsed().as_secs_f64())
+                                < min_speed
+                        {
+                            return (_iteration + 1, true);
+                        }
+                    }
+                }
+                (bma_benchmark_iterations, false)
+            })()
+        };
+        $crate::staged_benchmark_finish!($name, bma_benchmark_completed);
+        if bma_benchmark_aborted {
+            $crate::default_staged_benchmark().mark_aborted($name);
+        } else if bma_benchmark_completed < bma_benchmark_iterations {
+            $crate::default_staged_benchmark().mark_timed_out($name);
+        }
     };
 }
 
 #[macro_export]
 /// run a stage of staged bechmark and check the result for each iteration
 ///
-/// The statement MUST return true for ok and false for errors
+/// The statement MUST return true for ok and false for errors. If the stage timeout is set for the
+/// default staged benchmark, the stage is stopped after the timeout is exceeded. If neither
+/// timeout, abort nor per-stage warmup is set, the code is run in a plain loop
 macro_rules! staged_benchmark_check {
     ($name: expr, $iterations: expr, $code: block) => {
-        let mut bma_benchmark_errors = 0;
-        $crate::staged_benchmark_start!($name);
-        black_box(move || {
-            for _iteration in 0..$iterations {
-                if !$code {
-                    bma_benchmark_errors += 1;
+        let bma_benchmark_iterations: u64 = $crate::scale_iterations($iterations);
+        let (bma_benchmark_timeout, bma_benchmark_abort, bma_benchmark_warmup) = {
+            let staged_benchmark = $crate::default_staged_benchmark();
+            (
+                staged_benchmark.stage_timeout(),
+                staged_benchmark.abort_check(bma_benchmark_iterations),
+                staged_benchmark.per_stage_warmup(),
+            )
+        };
+        let (
+            bma_benchmark_errors,
+            bma_benchmark_first_error,
+            bma_benchmark_completed,
+            bma_benchmark_aborted,
+        ) = if bma_benchmark_timeout.is_none()
+            && bma_benchmark_abort.is_none()
+            && bma_benchmark_warmup.is_zero()
+        {
+            $crate::staged_benchmark_start!($name);
+            black_box(move || {
+                let mut errors: u64 = 0;
+                let mut first_error: Option<u64> = None;
+                for _iteration in 0..bma_benchmark_iterations {
+                    if !$code {
+                        if errors == 0 {
+                            first_error = Some(_iteration);
+                        }
+                        errors += 1;
+                    }
+                }
+                (errors, first_error, bma_benchmark_iterations, false)
+            })()
+        } else {
+            #[allow(unused_mut)]
+            let mut bma_benchmark_stage = black_box(move || $code);
+            $crate::staged_benchmark_warmup!(bma_benchmark_warmup, bma_benchmark_stage);
+            $crate::staged_benchmark_start!($name);
+            black_box(move || {
+                let started = ::std::time::Instant::now();
+                let mut errors: u64 = 0;
+                let mut first_error: Option<u64> = None;
+                for _iteration in 0..bma_benchmark_iterations {
+                    if !bma_benchmark_stage() {
+                        if errors == 0 {
+                            first_error = Some(_iteration);
+                        }
+                        errors += 1;
+                    }
+                    if let Some(timeout) = bma_benchmark_timeout {
+                        if started.elapsed() >= timeout {
+                            return (errors, first_error, _iteration + 1, false);
+                        }
+                    }
+                    if let Some((window, min_speed)) = bma_benchmark_abort {
+                        if _iteration + 1 == window
+                            && ((_iteration + 1) as f64 / started.elapsed().as_secs_f64())
+                                < min_speed
+                        {
+                            return (errors, first_error, _iteration + 1, true);
+                        }
+                    }
                 }
+                (errors, first_error, bma_benchmark_iterations, false)
+            })()
+        };
+        {
+            let mut staged_benchmark = $crate::default_staged_benchmark();
+            staged_benchmark.finish_with_first_error(
+                $name,
+                bma_benchmark_completed,
+                bma_benchmark_errors,
+                bma_benchmark_first_error,
+            );
+            if bma_benchmark_aborted {
+                staged_benchmark.mark_aborted($name);
+            } else if bma_benchmark_completed < bma_benchmark_iterations {
+                staged_benchmark.mark_timed_out($name);
+            }
+        }
+    };
+}
+
+#[macro_export]
+/// run named code blocks as stages of the default staged benchmark and print the results
+///
+/// ```rust,ignore
+/// staged_benchmark_compare!(10_000, {
+///     "mutex" => { let _a = mutex.lock().unwrap(); },
+///     "rwlock" => { let _a = rwlock.read().unwrap(); },
+/// });
+/// ```
+macro_rules! staged_benchmark_compare {
+    ($iterations: expr, { $($name: expr => $code: block),+ $(,)? }) => {{
+        let bma_benchmark_compare_iterations: u64 = $iterations;
+        $(
+            $crate::staged_benchmark!($name, bma_benchmark_compare_iterations, $code);
+        )+
+        $crate::staged_benchmark_print!();
+    }};
+}
+
+#[macro_export]
+/// run a stage of staged bechmark, recording duration of each iteration
+///
+/// Allows to get percentiles of stage iterations. Timing each iteration adds an overhead, so the
+/// stage speed is lower than for the normal stages
+macro_rules! staged_benchmark_sampled {
+    ($name: expr, $iterations: expr, $code: block) => {
+        let bma_benchmark_iterations: u64 = $iterations;
+        $crate::staged_benchmark_start!($name);
+        let bma_benchmark_samples = black_box(move || {
+            let mut samples =
+                Vec::with_capacity(usize::try_from(bma_benchmark_iterations).unwrap_or_default());
+            for _iteration in 0..bma_benchmark_iterations {
+                let started = ::std::time::Instant::now();
+                $code
+                samples.push(started.elapsed());
             }
+            samples
         })();
-        $crate::staged_benchmark_finish!($name, $iterations, bma_benchmark_errors);
+        $crate::default_staged_benchmark().finish_sampled(
+            $name,
+            bma_benchmark_iterations,
+            0,
+            bma_benchmark_samples,
+        );
+    };
+}
+
+#[macro_export]
+/// run a stage of staged bechmark and compare the result of each iteration with the expected value
+///
+/// The expected value is evaluated once, iterations which results are not equal to it are
+/// counted as errors
+macro_rules! staged_benchmark_expect {
+    ($name: expr, $iterations: expr, $expected: expr, $code: block) => {
+        let bma_benchmark_expected = $expected;
+        $crate::staged_benchmark_check!($name, $iterations, { ($code) == bma_benchmark_expected });
+    };
+}
+
+#[cfg(feature = "async")]
+#[macro_export]
+/// run a stage of staged benchmark for async code, must be called inside an async context
+///
+/// The closure MUST return a future, which is awaited sequentially for each iteration (no tasks
+/// are spawned), so the timing reflects serial latency
+macro_rules! async_staged_benchmark {
+    ($name: expr, $iterations: expr, $future_fn: expr) => {
+        let bma_benchmark_iterations: u64 = $iterations;
+        $crate::staged_benchmark_start!($name);
+        {
+            #[allow(unused_mut)]
+            let mut future_fn = $future_fn;
+            for _iteration in 0..bma_benchmark_iterations {
+                ::std::hint::black_box(future_fn().await);
+            }
+        }
+        $crate::staged_benchmark_finish!($name, bma_benchmark_iterations);
     };
 }
 
 #[macro_export]
 /// run a benchmark
+///
+/// If the number of iterations is not specified, it is auto-tuned (see [`benchmark_auto`]). With
+/// `check` flag, the code block must return a bool (see [`benchmark_check`])
 macro_rules! benchmark {
+    ($code: block) => {
+        $crate::benchmark_auto!($code);
+    };
+    ($iterations: expr, check, $code: block) => {
+        $crate::benchmark_check!($iterations, $code);
+    };
     ($iterations: expr, $code: block) => {
+        let bma_benchmark_iterations: u64 = $crate::scale_iterations($iterations);
         $crate::benchmark_start!();
         black_box(move || {
-        for _iteration in 0..$iterations
+        for _iteration in 0..bma_benchmark_iterations
             $code
         })();
-        $crate::benchmark_print!($iterations);
+        $crate::benchmark_print!(bma_benchmark_iterations);
+    };
+}
+
+#[macro_export]
+/// run a benchmark with untimed per-iteration setup
+///
+/// The setup expression is evaluated before each iteration, its value is passed to the code
+/// (a closure or a function) by value. Only the code execution is timed
+///
+/// ```rust,ignore
+/// benchmark_with_setup!(10_000, vec![3, 2, 1], |mut v: Vec<u32>| v.sort());
+/// ```
+macro_rules! benchmark_with_setup {
+    ($iterations: expr, $setup: expr, $code: expr) => {
+        let bma_benchmark_iterations: u64 = $iterations;
+        $crate::benchmark_start!();
+        let bma_benchmark_elapsed = black_box(move || {
+            #[allow(unused_mut)]
+            let mut code = $code;
+            let mut elapsed = ::std::time::Duration::ZERO;
+            for _iteration in 0..bma_benchmark_iterations {
+                let input = $setup;
+                let started = ::std::time::Instant::now();
+                black_box(code(input));
+                elapsed += started.elapsed();
+            }
+            elapsed
+        })();
+        {
+            let mut benchmark = $crate::default_benchmark();
+            benchmark.finish(Some(bma_benchmark_iterations), None);
+            benchmark.set_elapsed(bma_benchmark_elapsed);
+            benchmark.print0();
+        }
+    };
+}
+
+#[macro_export]
+/// run a benchmark without black box, the loop is executed directly
+///
+/// Useful for debugging or when it is required to let the optimizer see through the code. Note
+/// that the code without side effects may be optimized away, so the results may be meaningless
+macro_rules! benchmark_raw {
+    ($iterations: expr, $code: block) => {
+        let bma_benchmark_iterations: u64 = $iterations;
+        $crate::benchmark_start!();
+        for _iteration in 0..bma_benchmark_iterations
+            $code
+        $crate::benchmark_print!(bma_benchmark_iterations);
     };
 }
 
@@ -84,16 +591,311 @@ macro_rules! benchmark {
 /// The statement MUST return true for ok and false for errors
 macro_rules! benchmark_check {
     ($iterations: expr, $code: block) => {
-        let mut bma_benchmark_errors = 0;
+        let bma_benchmark_iterations: u64 = $crate::scale_iterations($iterations);
         $crate::benchmark_start!();
-        black_box(move || {
-            for _iteration in 0..$iterations {
+        let (bma_benchmark_errors, bma_benchmark_first_error) = black_box(move || {
+            let mut errors: u64 = 0;
+            let mut first_error: Option<u64> = None;
+            for _iteration in 0..bma_benchmark_iterations {
                 if !$code {
-                    bma_benchmark_errors += 1;
+                    if errors == 0 {
+                        first_error = Some(_iteration);
+                    }
+                    errors += 1;
+                }
+            }
+            (errors, first_error)
+        })();
+        {
+            let mut benchmark = $crate::default_benchmark();
+            benchmark.finish_with_first_error(
+                Some(bma_benchmark_iterations),
+                Some(bma_benchmark_errors),
+                bma_benchmark_first_error,
+            );
+            benchmark.print0();
+        }
+    };
+}
+
+#[macro_export]
+/// run a benchmark, the code block must return Result, Err results are counted as errors
+macro_rules! benchmark_try {
+    ($iterations: expr, $code: block) => {
+        let bma_benchmark_iterations: u64 = $iterations;
+        $crate::benchmark_start!();
+        let (bma_benchmark_errors, bma_benchmark_first_error) = black_box(move || {
+            let mut errors: u64 = 0;
+            let mut first_error: Option<u64> = None;
+            for _iteration in 0..bma_benchmark_iterations {
+                match $code {
+                    Ok(v) => {
+                        black_box(v);
+                    }
+                    Err(_) => {
+                        if errors == 0 {
+                            first_error = Some(_iteration);
+                        }
+                        errors += 1;
+                    }
+                }
+            }
+            (errors, first_error)
+        })();
+        {
+            let mut benchmark = $crate::default_benchmark();
+            benchmark.finish_with_first_error(
+                Some(bma_benchmark_iterations),
+                Some(bma_benchmark_errors),
+                bma_benchmark_first_error,
+            );
+            benchmark.print0();
+        }
+    };
+}
+
+#[macro_export]
+/// run a benchmark for the specified duration and count iterations made
+///
+/// The code block is executed at least once. Note that checking the elapsed time after each
+/// iteration adds a small overhead
+macro_rules! benchmark_for {
+    ($duration: expr, $code: block) => {
+        let bma_benchmark_duration: ::std::time::Duration = $duration;
+        $crate::benchmark_start!();
+        let bma_benchmark_iterations = black_box(move || {
+            let started = ::std::time::Instant::now();
+            let mut iterations: u64 = 0;
+            loop {
+                $code
+                iterations += 1;
+                if started.elapsed() >= bma_benchmark_duration {
+                    break iterations;
+                }
+            }
+        })();
+        {
+            let mut benchmark = $crate::default_benchmark();
+            benchmark.finish(Some(bma_benchmark_iterations), None);
+            benchmark.print0();
+        }
+    };
+}
+
+#[macro_export]
+/// run a benchmark, choosing the number of iterations automatically
+///
+/// The number of iterations starts from 1 and is doubled until a run takes at least the target
+/// duration (100 ms by default), the last run results are reported. The time of the previous
+/// runs is reported separately as the tuning time
+macro_rules! benchmark_auto {
+    ($code: block) => {
+        $crate::benchmark_auto!($code, ::std::time::Duration::from_millis(100));
+    };
+    ($code: block, $target: expr) => {
+        let bma_benchmark_target: ::std::time::Duration = $target;
+        #[allow(unused_mut)]
+        let mut bma_benchmark_run = black_box(move |iterations: u64| {
+            for _iteration in 0..iterations
+                $code
+        });
+        let mut bma_benchmark_iterations: u64 = 1;
+        let mut bma_benchmark_tuning = ::std::time::Duration::ZERO;
+        loop {
+            $crate::benchmark_start!();
+            let started = ::std::time::Instant::now();
+            bma_benchmark_run(bma_benchmark_iterations);
+            let elapsed = started.elapsed();
+            if elapsed >= bma_benchmark_target || bma_benchmark_iterations == u64::MAX {
+                break;
+            }
+            bma_benchmark_tuning += elapsed;
+            bma_benchmark_iterations = bma_benchmark_iterations.saturating_mul(2);
+        }
+        {
+            let mut benchmark = $crate::default_benchmark();
+            benchmark.finish(Some(bma_benchmark_iterations), None);
+            benchmark.set_tuning_elapsed(bma_benchmark_tuning);
+            benchmark.print0();
+        }
+    };
+}
+
+#[macro_export]
+/// run a benchmark and print progress to stderr every N iterations
+///
+/// The progress line is printed only if stderr is a terminal
+macro_rules! benchmark_progress {
+    ($iterations: expr, $interval: expr, $code: block) => {
+        let bma_benchmark_iterations: u64 = $iterations;
+        let bma_benchmark_interval: u64 = $interval;
+        $crate::benchmark_start!();
+        black_box(move || {
+            let started = ::std::time::Instant::now();
+            for _iteration in 0..bma_benchmark_iterations {
+                $code
+                if bma_benchmark_interval > 0 && (_iteration + 1) % bma_benchmark_interval == 0 {
+                    $crate::print_progress(
+                        _iteration + 1,
+                        bma_benchmark_iterations,
+                        started.elapsed(),
+                    );
                 }
             }
+            if bma_benchmark_interval > 0 && bma_benchmark_iterations % bma_benchmark_interval != 0 {
+                $crate::print_progress(
+                    bma_benchmark_iterations,
+                    bma_benchmark_iterations,
+                    started.elapsed(),
+                );
+            }
+        })();
+        $crate::benchmark_print!(bma_benchmark_iterations);
+    };
+}
+
+#[cfg(all(feature = "x86", target_arch = "x86_64"))]
+#[macro_export]
+/// run a benchmark and report estimated CPU cycles per iteration
+///
+/// The cycle count is read from the CPU timestamp counter, which ticks at a constant rate on
+/// modern CPUs, so the value is an estimate affected by frequency scaling (turbo boost, power
+/// saving etc.)
+macro_rules! benchmark_cycles {
+    ($iterations: expr, $code: block) => {
+        let bma_benchmark_iterations: u64 = $iterations;
+        $crate::benchmark_start!();
+        let bma_benchmark_cycles = black_box(move || {
+            let started = $crate::rdtsc();
+            for _iteration in 0..bma_benchmark_iterations
+                $code
+            $crate::rdtsc().wrapping_sub(started)
+        })();
+        $crate::benchmark_print!(bma_benchmark_iterations);
+        $crate::print_cycles(bma_benchmark_cycles, bma_benchmark_iterations);
+    };
+}
+
+#[cfg(feature = "alloc-count")]
+#[macro_export]
+/// run a benchmark and report memory allocations per iteration
+///
+/// Requires [`CountingAllocator`] to be registered as the global allocator. Only allocations made
+/// by the current thread are counted
+macro_rules! benchmark_allocs {
+    ($iterations: expr, $code: block) => {
+        let bma_benchmark_iterations: u64 = $iterations;
+        $crate::benchmark_start!();
+        let (bma_benchmark_allocs, bma_benchmark_alloc_bytes) = black_box(move || {
+            $crate::CountingAllocator::reset();
+            for _iteration in 0..bma_benchmark_iterations
+                $code
+            $crate::CountingAllocator::counters()
+        })();
+        $crate::benchmark_print!(bma_benchmark_iterations);
+        $crate::print_allocs(
+            bma_benchmark_allocs,
+            bma_benchmark_alloc_bytes,
+            bma_benchmark_iterations,
+        );
+    };
+}
+
+#[macro_export]
+/// run a benchmark and report the peak RSS increase (Linux only)
+///
+/// The peak resident set size (VmHWM) is read from /proc/self/status before and after the run. On
+/// other platforms the plain benchmark is run and a note is printed instead
+macro_rules! benchmark_rss {
+    ($iterations: expr, $code: block) => {
+        let bma_benchmark_iterations: u64 = $iterations;
+        let bma_benchmark_rss = $crate::peak_rss();
+        $crate::benchmark_start!();
+        black_box(move || {
+        for _iteration in 0..bma_benchmark_iterations
+            $code
+        })();
+        $crate::benchmark_print!(bma_benchmark_iterations);
+        $crate::print_rss(bma_benchmark_rss, $crate::peak_rss());
+    };
+}
+
+#[macro_export]
+/// run a benchmark and report throughput, specifying bytes processed per iteration
+macro_rules! benchmark_bytes {
+    ($iterations: expr, $bytes: expr, $code: block) => {
+        let bma_benchmark_iterations: u64 = $iterations;
+        $crate::benchmark_start!();
+        black_box(move || {
+        for _iteration in 0..bma_benchmark_iterations
+            $code
+        })();
+        {
+            let mut benchmark = $crate::default_benchmark();
+            benchmark.finish_bytes(Some(bma_benchmark_iterations), $bytes);
+            benchmark.print0();
+        }
+    };
+}
+
+#[macro_export]
+/// run a benchmark, consuming an iterator (each item is an iteration)
+macro_rules! benchmark_iter {
+    ($iter: expr) => {
+        $crate::benchmark_start!();
+        let bma_benchmark_iterations = {
+            let mut iterations: u64 = 0;
+            for item in $iter {
+                ::std::hint::black_box(item);
+                iterations += 1;
+            }
+            iterations
+        };
+        {
+            let mut benchmark = $crate::default_benchmark();
+            benchmark.finish(Some(bma_benchmark_iterations), None);
+            benchmark.print0();
+        }
+    };
+}
+
+#[macro_export]
+/// run a benchmark and return the value produced by the last iteration
+///
+/// # Panics
+///
+/// Will panic if the number of iterations is zero
+macro_rules! benchmark_value {
+    ($iterations: expr, $code: block) => {{
+        let bma_benchmark_iterations: u64 = $iterations;
+        $crate::benchmark_start!();
+        let bma_benchmark_value = black_box(move || {
+            let mut value = None;
+            for _iteration in 0..bma_benchmark_iterations {
+                value = Some(::std::hint::black_box($code));
+            }
+            value
+        })();
+        $crate::benchmark_print!(bma_benchmark_iterations);
+        bma_benchmark_value.expect("benchmark_value requires at least one iteration")
+    }};
+}
+
+#[macro_export]
+/// run a benchmark with a label, which is displayed in the results
+macro_rules! benchmark_labeled {
+    ($label: expr, $iterations: expr, $code: block) => {
+        let bma_benchmark_iterations: u64 = $iterations;
+        {
+            let mut benchmark = $crate::default_benchmark();
+            benchmark.reset();
+            benchmark.set_label(Some($label));
+        }
+        black_box(move || {
+        for _iteration in 0..bma_benchmark_iterations
+            $code
         })();
-        $crate::benchmark_print!($iterations, bma_benchmark_errors);
+        $crate::benchmark_print!(bma_benchmark_iterations);
     };
 }
 
@@ -101,10 +903,7 @@ macro_rules! benchmark_check {
 #[macro_export]
 macro_rules! staged_benchmark_start {
     ($name: expr) => {
-        $crate::DEFAULT_STAGED_BENCHMARK
-            .lock()
-            .unwrap()
-            .start($name);
+        $crate::default_staged_benchmark().start($name);
     };
 }
 
@@ -112,16 +911,10 @@ macro_rules! staged_benchmark_start {
 #[macro_export]
 macro_rules! staged_benchmark_finish {
     ($name: expr, $iterations: expr) => {
-        $crate::DEFAULT_STAGED_BENCHMARK
-            .lock()
-            .unwrap()
-            .finish($name, $iterations, 0);
+        $crate::default_staged_benchmark().finish($name, $iterations, 0);
     };
     ($name: expr, $iterations: expr, $errors: expr) => {
-        $crate::DEFAULT_STAGED_BENCHMARK
-            .lock()
-            .unwrap()
-            .finish($name, $iterations, $errors);
+        $crate::default_staged_benchmark().finish($name, $iterations, $errors);
     };
 }
 
@@ -129,24 +922,21 @@ macro_rules! staged_benchmark_finish {
 #[macro_export]
 macro_rules! staged_benchmark_finish_current {
     ($iterations: expr) => {
-        $crate::DEFAULT_STAGED_BENCHMARK
-            .lock()
-            .unwrap()
-            .finish_current($iterations, 0);
+        $crate::default_staged_benchmark().finish_current($iterations, 0);
     };
     ($iterations: expr, $errors: expr) => {
-        $crate::DEFAULT_STAGED_BENCHMARK
-            .lock()
-            .unwrap()
-            .finish_current($iterations, $errors);
+        $crate::default_staged_benchmark().finish_current($iterations, $errors);
     };
 }
 
 /// Reset the default staged benchmark
+///
+/// As the default staged benchmark is global, it must be reset between independent benchmark
+/// sequences, otherwise results are mixed and reusing a stage name causes panic
 #[macro_export]
 macro_rules! staged_benchmark_reset {
     () => {
-        $crate::DEFAULT_STAGED_BENCHMARK.lock().unwrap().reset();
+        $crate::default_staged_benchmark().reset();
     };
 }
 
@@ -154,7 +944,7 @@ macro_rules! staged_benchmark_reset {
 #[macro_export]
 macro_rules! staged_benchmark_print {
     () => {
-        $crate::DEFAULT_STAGED_BENCHMARK.lock().unwrap().print();
+        $crate::default_staged_benchmark().print();
     };
 }
 
@@ -162,42 +952,175 @@ macro_rules! staged_benchmark_print {
 #[macro_export]
 macro_rules! staged_benchmark_print_for {
     ($eta: expr) => {
-        $crate::DEFAULT_STAGED_BENCHMARK
-            .lock()
-            .unwrap()
-            .print_for($eta);
+        $crate::default_staged_benchmark().print_for($eta);
+    };
+}
+
+/// Print staged benchmark result as CSV
+#[macro_export]
+macro_rules! staged_benchmark_csv {
+    () => {
+        print!("{}", $crate::default_staged_benchmark().result_csv());
     };
 }
 
+/// Reset the default simple benchmark
+#[macro_export]
+macro_rules! benchmark_reset {
+    () => {{
+        let mut benchmark = $crate::default_benchmark();
+        benchmark.reset();
+        benchmark.set_label(None);
+    }};
+}
+
 /// Start a simple benchmark
 #[macro_export]
 macro_rules! benchmark_start {
     () => {
-        $crate::DEFAULT_BENCHMARK.lock().unwrap().reset();
+        $crate::benchmark_reset!();
     };
 }
 
 /// Finish a simple benchmark and print results
 #[macro_export]
 macro_rules! benchmark_print {
-    ($iterations: expr) => {
-        $crate::DEFAULT_BENCHMARK
-            .lock()
-            .unwrap()
-            .print(Some($iterations), None);
+    ($iterations: expr) => {{
+        let mut benchmark = $crate::default_benchmark();
+        benchmark.finish(Some($iterations), None);
+        benchmark.print0();
+    }};
+    ($iterations: expr, $errors: expr) => {{
+        let mut benchmark = $crate::default_benchmark();
+        benchmark.finish(Some($iterations), Some($errors));
+        benchmark.print0();
+    }};
+}
+
+/// Finish a simple benchmark and print results in one line
+#[macro_export]
+macro_rules! benchmark_print_oneline {
+    ($iterations: expr) => {{
+        let mut benchmark = $crate::default_benchmark();
+        benchmark.finish(Some($iterations), None);
+        benchmark.print_oneline();
+    }};
+    ($iterations: expr, $errors: expr) => {{
+        let mut benchmark = $crate::default_benchmark();
+        benchmark.finish(Some($iterations), Some($errors));
+        benchmark.print_oneline();
+    }};
+}
+
+/// Start a named simple benchmark (an existing one with the same name is replaced)
+#[macro_export]
+macro_rules! benchmark_named_start {
+    ($name: expr) => {
+        $crate::named_benchmarks().insert(($name).to_owned(), $crate::Benchmark::new0());
     };
-    ($iterations: expr, $errors: expr) => {
-        $crate::DEFAULT_BENCHMARK
-            .lock()
-            .unwrap()
-            .print(Some($iterations), Some($errors));
+}
+
+/// Finish a named simple benchmark, the results are printed with
+/// [`benchmark_named_print_all`]
+///
+/// Panics if the benchmark is not started
+#[macro_export]
+macro_rules! benchmark_named_finish {
+    ($name: expr, $iterations: expr) => {
+        $crate::benchmark_named_finish!($name, $iterations, 0)
+    };
+    ($name: expr, $iterations: expr, $errors: expr) => {{
+        let name: &str = $name;
+        $crate::named_benchmarks()
+            .get_mut(name)
+            .unwrap_or_else(|| panic!("Benchmark {} not found", name))
+            .finish(Some($iterations), Some($errors));
+    }};
+}
+
+/// Print all named simple benchmarks as a combined table
+#[macro_export]
+macro_rules! benchmark_named_print_all {
+    () => {
+        $crate::print_named_benchmarks();
+    };
+}
+
+/// Time a code block, returns (value, elapsed) tuple, see [`Benchmark::time`]
+#[macro_export]
+macro_rules! time_it {
+    ($code: block) => {
+        $crate::Benchmark::time(|| $code)
+    };
+}
+
+/// Get the result of the default simple benchmark
+#[macro_export]
+macro_rules! benchmark_result {
+    () => {
+        $crate::default_benchmark().result0()
+    };
+}
+
+/// Get results of the default staged benchmark, mapped by stage names
+#[macro_export]
+macro_rules! staged_benchmark_results {
+    () => {
+        $crate::default_staged_benchmark().results()
     };
 }
 
-#[derive(Default)]
+/// Latency benchmark errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LatencyError {
+    /// op_finish has been called without op_start
+    NoOpStarted,
+}
+
+impl fmt::Display for LatencyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LatencyError::NoOpStarted => write!(f, "no latency op started"),
+        }
+    }
+}
+
+impl std::error::Error for LatencyError {}
+
+/// Staged benchmark errors
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StageError {
+    /// a stage with the same name already exists
+    AlreadyExists(String),
+}
+
+impl fmt::Display for StageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StageError::AlreadyExists(name) => write!(f, "Benchmark stage {} already exists", name),
+        }
+    }
+}
+
+impl std::error::Error for StageError {}
+
+#[derive(Clone)]
 pub struct LatencyBenchmark {
-    latencies: Vec<Duration>,
+    latencies: VecDeque<Duration>,
+    window: Option<usize>,
     op: Option<Instant>,
+    color: bool,
+}
+
+impl Default for LatencyBenchmark {
+    fn default() -> Self {
+        Self {
+            latencies: VecDeque::new(),
+            window: None,
+            op: None,
+            color: true,
+        }
+    }
 }
 
 impl LatencyBenchmark {
@@ -205,10 +1128,57 @@ impl LatencyBenchmark {
     pub fn new() -> Self {
         Self::default()
     }
+    /// Create a latency benchmark which keeps only the last N samples (clamped to at least 1)
+    ///
+    /// All the stats reflect the recent samples only
+    pub fn windowed(window: usize) -> Self {
+        let window = window.max(1);
+        Self {
+            latencies: VecDeque::with_capacity(window),
+            window: Some(window),
+            op: None,
+            color: true,
+        }
+    }
+    /// Enable or disable colored output for this benchmark (enabled by default)
+    pub fn set_color(&mut self, color: bool) {
+        self.color = color;
+    }
     pub fn clear(&mut self) {
         self.latencies.clear();
         self.op.take();
     }
+    /// Get the number of recorded latencies
+    pub fn len(&self) -> usize {
+        self.latencies.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.latencies.is_empty()
+    }
+    /// Call the function for each recorded latency, removing the samples
+    pub fn drain_each(&mut self, mut f: impl FnMut(Duration)) {
+        for latency in self.latencies.drain(..) {
+            f(latency);
+        }
+    }
+    /// Take recorded latencies out, leaving the benchmark empty
+    pub fn take_latencies(&mut self) -> Vec<Duration> {
+        let latencies = std::mem::take(&mut self.latencies);
+        if let Some(window) = self.window {
+            self.latencies.reserve(window);
+        }
+        latencies.into()
+    }
+    /// Start measuring a scope, the latency is recorded when the returned guard is dropped
+    ///
+    /// If the guard is forgotten (e.g. with [`std::mem::forget`]), nothing is recorded
+    #[inline]
+    pub fn measure(&mut self) -> LatencyGuard<'_> {
+        LatencyGuard {
+            benchmark: self,
+            started: Instant::now(),
+        }
+    }
     #[inline]
     pub fn op_start(&mut self) {
         self.op.replace(Instant::now());
@@ -218,11 +1188,23 @@ impl LatencyBenchmark {
     /// Will panic if op is not started
     #[inline]
     pub fn op_finish(&mut self) {
-        self.latencies.push(self.op.take().unwrap().elapsed());
+        self.try_op_finish().unwrap();
+    }
+    /// # Errors
+    ///
+    /// Will return [`LatencyError::NoOpStarted`] if op is not started
+    #[inline]
+    pub fn try_op_finish(&mut self) -> Result<(), LatencyError> {
+        let op = self.op.take().ok_or(LatencyError::NoOpStarted)?;
+        self.push(op.elapsed());
+        Ok(())
     }
     #[inline]
     pub fn push(&mut self, latency: Duration) {
-        self.latencies.push(latency);
+        if self.window == Some(self.latencies.len()) {
+            self.latencies.pop_front();
+        }
+        self.latencies.push_back(latency);
     }
     #[allow(clippy::cast_possible_truncation)]
     pub fn avg(&self) -> Duration {
@@ -234,25 +1216,566 @@ impl LatencyBenchmark {
     pub fn max(&self) -> Duration {
         self.latencies.iter().max().copied().unwrap_or_default()
     }
+    /// Get a latency percentile (0.0 - 100.0), interpolating between samples
+    pub fn percentile(&self, p: f64) -> Duration {
+        let mut sorted: Vec<Duration> = self.latencies.iter().copied().collect();
+        sorted.sort_unstable();
+        percentile_of(&sorted, p)
+    }
+    #[inline]
+    pub fn p50(&self) -> Duration {
+        self.percentile(50.0)
+    }
+    #[inline]
+    pub fn p90(&self) -> Duration {
+        self.percentile(90.0)
+    }
+    #[inline]
+    pub fn p99(&self) -> Duration {
+        self.percentile(99.0)
+    }
+    #[inline]
+    pub fn p999(&self) -> Duration {
+        self.percentile(99.9)
+    }
     pub fn print(&self) {
+        self.print_to(&mut io::stdout())
+            .expect("failed printing to stdout");
+    }
+    /// Print latency results to the specified writer
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the writer fails
+    pub fn print_to(&self, w: &mut dyn Write) -> io::Result<()> {
+        init_color();
         let avg = format_number!(self.avg().as_micros()).yellow();
         let min = format_number!(self.min().as_micros()).green();
         let max = format_number!(self.max().as_micros()).red();
-        println!("latency (μs) avg: {}, min: {}, max: {}", avg, min, max);
+        let p50 = format_number!(self.p50().as_micros()).cyan();
+        let p99 = format_number!(self.p99().as_micros()).magenta();
+        writeln!(
+            &mut ColorWriter::new(w, self.color),
+            "latency (μs) avg: {}, min: {}, max: {}, p50: {}, p99: {}",
+            avg,
+            min,
+            max,
+            p50,
+            p99
+        )
+    }
+    #[cfg(feature = "serde")]
+    /// Write recorded latencies as JSON lines (`{"latency_us": N}`), in insertion order
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the writer fails
+    pub fn write_jsonl(&self, w: &mut dyn Write) -> io::Result<()> {
+        #[derive(serde::Serialize)]
+        struct LatencyRecord {
+            latency_us: u128,
+        }
+        for latency in &self.latencies {
+            serde_json::to_writer(
+                &mut *w,
+                &LatencyRecord {
+                    latency_us: latency.as_micros(),
+                },
+            )?;
+            writeln!(w)?;
+        }
+        Ok(())
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    /// Split latencies into evenly sized buckets between min and max
+    ///
+    /// Returns (bucket start, bucket end, number of samples) for each bucket. The number of
+    /// buckets is clamped to at least 1
+    pub fn histogram(&self, buckets: usize) -> Vec<(Duration, Duration, usize)> {
+        if self.latencies.is_empty() {
+            return Vec::new();
+        }
+        let buckets = buckets.max(1);
+        let min = self.min().as_nanos();
+        let span = self.max().as_nanos() - min;
+        let bound =
+            |i: usize| Duration::from_nanos((min + span * i as u128 / buckets as u128) as u64);
+        let mut counts = vec![0; buckets];
+        for latency in &self.latencies {
+            let idx = ((latency.as_nanos() - min) * buckets as u128)
+                .checked_div(span)
+                .unwrap_or_default() as usize;
+            counts[idx.min(buckets - 1)] += 1;
+        }
+        counts
+            .into_iter()
+            .enumerate()
+            .map(|(i, count)| (bound(i), bound(i + 1), count))
+            .collect()
+    }
+    /// Print latency histogram, bars are scaled to the terminal width
+    pub fn print_histogram(&self, buckets: usize) {
+        self.print_histogram_to(&mut io::stdout(), buckets)
+            .expect("failed printing to stdout");
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    /// Print latency histogram to the specified writer, bars are scaled to the terminal width
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the writer fails
+    pub fn print_histogram_to(&self, w: &mut dyn Write, buckets: usize) -> io::Result<()> {
+        init_color();
+        let mut w = ColorWriter::new(w, self.color);
+        let histogram = self.histogram(buckets);
+        if histogram.is_empty() {
+            return writeln!(w, "{}", "no samples".black());
+        }
+        let labels: Vec<String> = histogram
+            .iter()
+            .map(|(from, to, count)| {
+                format!(
+                    "{} - {} μs ({})",
+                    format_number!(from.as_micros()),
+                    format_number!(to.as_micros()),
+                    format_number!(*count)
+                )
+            })
+            .collect();
+        let label_width = labels
+            .iter()
+            .map(|l| l.chars().count())
+            .max()
+            .unwrap_or_default();
+        let bar_width = usize::from(output_width())
+            .saturating_sub(label_width + 1)
+            .max(1);
+        let max_count = histogram
+            .iter()
+            .map(|v| v.2)
+            .max()
+            .unwrap_or_default()
+            .max(1);
+        for (label, (_, _, count)) in labels.iter().zip(histogram) {
+            writeln!(
+                w,
+                "{:<width$} {}",
+                label,
+                "#".repeat(count * bar_width / max_count).cyan(),
+                width = label_width
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Records latency of a scope into [`LatencyBenchmark`] on drop
+pub struct LatencyGuard<'a> {
+    benchmark: &'a mut LatencyBenchmark,
+    started: Instant,
+}
+
+impl Drop for LatencyGuard<'_> {
+    fn drop(&mut self) {
+        self.benchmark.push(self.started.elapsed());
+    }
+}
+
+/// Thread-safe latency benchmark, which can be cloned and used from multiple threads
+///
+/// Clones share the recorded latencies. Ops are tracked per thread, so concurrent ops do not
+/// affect each other. Started ops are owned by the benchmark and are released with it
+#[derive(Clone)]
+pub struct SharedLatencyBenchmark {
+    inner: std::sync::Arc<Mutex<LatencyBenchmark>>,
+    ops: std::sync::Arc<Mutex<HashMap<std::thread::ThreadId, Instant>>>,
+}
+
+impl Default for SharedLatencyBenchmark {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SharedLatencyBenchmark {
+    pub fn new() -> Self {
+        Self {
+            inner: <_>::default(),
+            ops: <_>::default(),
+        }
+    }
+    fn lock(&self) -> MutexGuard<'_, LatencyBenchmark> {
+        self.inner
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+    fn lock_ops(&self) -> MutexGuard<'_, HashMap<std::thread::ThreadId, Instant>> {
+        self.ops
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+    pub fn push(&self, latency: Duration) {
+        self.lock().push(latency);
+    }
+    /// Start an op in the current thread
+    pub fn op_start(&self) {
+        let mut ops = self.lock_ops();
+        ops.insert(std::thread::current().id(), Instant::now());
+    }
+    /// # Panics
+    ///
+    /// Will panic if op is not started in the current thread
+    #[inline]
+    pub fn op_finish(&self) {
+        self.try_op_finish().unwrap();
+    }
+    /// # Errors
+    ///
+    /// Will return [`LatencyError::NoOpStarted`] if op is not started in the current thread
+    pub fn try_op_finish(&self) -> Result<(), LatencyError> {
+        let finished = Instant::now();
+        let op = self
+            .lock_ops()
+            .remove(&std::thread::current().id())
+            .ok_or(LatencyError::NoOpStarted)?;
+        self.push(finished.duration_since(op));
+        Ok(())
+    }
+    /// Get the number of latencies, recorded by all threads
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+    /// Get a copy of latencies, recorded by all threads, as a single latency benchmark
+    pub fn merge(&self) -> LatencyBenchmark {
+        self.lock().clone()
     }
+    /// Print latency results, aggregated across all threads
+    pub fn print(&self) {
+        self.lock().print();
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_sign_loss)]
+#[allow(clippy::cast_precision_loss)]
+/// Get a percentile from sorted samples, interpolating for fractional indices
+fn percentile_of(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::default();
+    }
+    let rank = p.clamp(0.0, 100.0) / 100.0 * (sorted.len() - 1) as f64;
+    let lower = rank.floor() as usize;
+    let upper = rank.ceil() as usize;
+    let lo = sorted[lower];
+    let hi = sorted[upper];
+    lo + (hi - lo).mul_f64(rank - lower as f64)
 }
 
 /// Benchmark results for a simple benchmark or a stage
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BenchmarkResult {
+    /// Time of the measured run, speed is calculated from it (in auto mode the tuning runs are
+    /// not included, see `tuning_elapsed`)
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "elapsed_secs", with = "duration_secs")
+    )]
     pub elapsed: Duration,
-    pub iterations: u32,
-    pub errors: u32,
-    pub speed: u32,
+    pub iterations: u64,
+    pub errors: u64,
+    pub speed: u64,
+    /// Average time per iteration (elapsed / iterations, zero if there were no iterations)
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "per_iter_secs", with = "duration_secs", default)
+    )]
+    pub per_iter: Duration,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub bytes_per_sec: Option<u64>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub items_per_sec: Option<u64>,
+    /// Elements (rows, messages etc.) processed per iteration
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub elements: Option<u64>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub median_speed: Option<u64>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub first_error: Option<u64>,
+    /// 95% confidence interval of iters/s (low, high), see [`BenchmarkResult::speed_ci95`]
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            rename = "speed_ci95",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )
+    )]
+    pub ci95: Option<(u64, u64)>,
+    /// Time spent on tuning the number of iterations before the measured run (auto mode only),
+    /// not included in elapsed and speed
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            rename = "tuning_elapsed_secs",
+            with = "duration_secs",
+            default,
+            skip_serializing_if = "Duration::is_zero"
+        )
+    )]
+    pub tuning_elapsed: Duration,
+}
+
+impl BenchmarkResult {
+    #[allow(clippy::cast_precision_loss)]
+    /// Get the error rate (0.0 - 1.0), returns 0.0 if there were no iterations
+    pub fn error_rate(&self) -> f64 {
+        if self.iterations == 0 {
+            0.0
+        } else {
+            self.errors as f64 / self.iterations as f64
+        }
+    }
+
+    /// Check if there were any errors
+    pub fn has_errors(&self) -> bool {
+        self.errors > 0
+    }
+
+    /// Print the result and terminate the process with exit code 1 if there were any errors,
+    /// does nothing otherwise
+    ///
+    /// Intended to be called at the end of a benchmark binary, e.g. to fail CI jobs. Note that the
+    /// process is terminated immediately, destructors are not run
+    pub fn exit_on_error(&self) {
+        if self.has_errors() {
+            println!("{}", self);
+            eprintln!("benchmark finished with {} error(s)", self.errors);
+            std::process::exit(1);
+        }
+    }
+
+    /// # Panics
+    ///
+    /// Will panic if the error rate (0.0 - 1.0) exceeds the specified maximum
+    pub fn assert_error_rate_below(&self, max_rate: f64) {
+        let rate = self.error_rate();
+        assert!(
+            rate <= max_rate,
+            "benchmark error rate {:.4} ({} of {} iterations) exceeds the allowed {:.4}",
+            rate,
+            self.errors,
+            self.iterations,
+            max_rate
+        );
+    }
+
+    #[allow(clippy::cast_sign_loss)]
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_precision_loss)]
+    /// Combine results of multiple runs into a single one
+    ///
+    /// Iterations, errors and elapsed time are summed, speed is recalculated from the totals.
+    /// Bytes / items per second are recalculated only if all the runs have them, median speed and
+    /// the first error are not merged. Elements per iteration are kept if equal for all the runs,
+    /// tuning time is summed.
+    /// An empty slice gives a zeroed result.
+    pub fn merge(results: &[BenchmarkResult]) -> BenchmarkResult {
+        let elapsed: Duration = results.iter().map(|r| r.elapsed).sum();
+        let iterations: u64 = results.iter().map(|r| r.iterations).sum();
+        let errors: u64 = results.iter().map(|r| r.errors).sum();
+        let secs = elapsed.as_secs_f64();
+        let per_sec = |value: f64| {
+            if secs > 0.0 {
+                rate_per_sec(value, secs)
+            } else {
+                0
+            }
+        };
+        let merge_rate = |rate: fn(&BenchmarkResult) -> Option<u64>| {
+            if results.is_empty() {
+                None
+            } else {
+                results
+                    .iter()
+                    .map(|r| rate(r).map(|v| v as f64 * r.elapsed.as_secs_f64()))
+                    .sum::<Option<f64>>()
+                    .map(per_sec)
+            }
+        };
+        BenchmarkResult {
+            elapsed,
+            iterations,
+            errors,
+            speed: per_sec((iterations - errors) as f64),
+            per_iter: per_iter_of(elapsed, iterations),
+            bytes_per_sec: merge_rate(|r| r.bytes_per_sec),
+            items_per_sec: merge_rate(|r| r.items_per_sec),
+            elements: results
+                .first()
+                .and_then(|first| first.elements)
+                .filter(|e| results.iter().all(|r| r.elements == Some(*e))),
+            median_speed: None,
+            first_error: None,
+            ci95: None,
+            tuning_elapsed: results.iter().map(|r| r.tuning_elapsed).sum(),
+        }
+    }
+
+    #[allow(clippy::cast_sign_loss)]
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_precision_loss)]
+    /// Get elements processed per second, if the number of elements per iteration is set
+    pub fn elements_per_sec(&self) -> Option<u64> {
+        let secs = self.elapsed.as_secs_f64();
+        self.elements.map(|elements| {
+            if secs > 0.0 {
+                rate_per_sec(
+                    (self.iterations - self.errors) as f64 * elements as f64,
+                    secs,
+                )
+            } else {
+                0
+            }
+        })
+    }
+
+    /// Get 95% confidence interval of iters/s (low, high), calculated from per-iteration samples
+    ///
+    /// Returns None if sampling is disabled or there are less than 2 samples
+    pub fn speed_ci95(&self) -> Option<(u64, u64)> {
+        self.ci95
+    }
+
+    /// Pick the result of the fastest run, an empty slice gives a zeroed result
+    pub fn merge_best(results: &[BenchmarkResult]) -> BenchmarkResult {
+        results
+            .iter()
+            .max_by_key(|r| r.speed)
+            .cloned()
+            .unwrap_or_else(|| Self::merge(&[]))
+    }
+}
+
+/// One-line format without colors, e.g. `iters=1_000_000 errs=0 elapsed=1.234s speed=810_372/s`
+impl fmt::Display for BenchmarkResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "iters={} errs={} elapsed={:.3}s speed={}/s",
+            format_number!(self.iterations),
+            format_number!(self.errors),
+            self.elapsed.as_secs_f64(),
+            format_number!(self.speed)
+        )
+    }
+}
+
+#[cfg(feature = "serde")]
+mod duration_secs {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S>(value: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_f64(value.as_secs_f64())
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let secs = f64::deserialize(deserializer)?;
+        Duration::try_from_secs_f64(secs).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+struct StageResult<'a> {
+    stage: &'a str,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    tags: &'a BTreeMap<String, String>,
+    #[serde(flatten)]
+    result: BenchmarkResult,
 }
 
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct LoadedStageResult {
+    stage: String,
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
+    #[serde(flatten)]
+    result: BenchmarkResult,
+}
+
+/// Staged benchmark result table columns
+///
+/// The stage column is always displayed, error columns are displayed only if there are errors
+#[derive(Debug, Clone)]
+pub struct StagedBenchmarkOptions {
+    pub show_iters: bool,
+    pub show_errors: bool,
+    pub show_secs: bool,
+    pub show_msecs: bool,
+    pub show_speed: bool,
+}
+
+impl Default for StagedBenchmarkOptions {
+    fn default() -> Self {
+        Self {
+            show_iters: true,
+            show_errors: true,
+            show_secs: true,
+            show_msecs: true,
+            show_speed: true,
+        }
+    }
+}
+
+const DEFAULT_DIFF_TOLERANCE: f64 = 0.0001;
+
+const DEFAULT_ABORT_WINDOW: f64 = 0.01;
+
+const NOTHING_MEASURED: &str = "0 iterations - nothing measured";
+
+/// Stage completion callback, see [`StagedBenchmark::on_stage_finish`]
+pub type StageFinishCallback = Box<dyn FnMut(&str, &BenchmarkResult) + Send>;
+
 /// Staged benchmark
 pub struct StagedBenchmark {
     benchmarks: BTreeMap<String, Benchmark>,
+    tags: BTreeMap<String, BTreeMap<String, String>>,
+    timed_out: BTreeSet<String>,
+    aborted: BTreeSet<String>,
+    abort_factor: Option<f64>,
+    abort_window: f64,
+    stage_timeout: Option<Duration>,
+    per_stage_warmup: Duration,
+    diff_tolerance: f64,
+    color: bool,
+    on_stage_finish: Option<StageFinishCallback>,
     current_stage: Option<String>,
 }
 
@@ -266,6 +1789,16 @@ impl StagedBenchmark {
     pub fn new() -> Self {
         Self {
             benchmarks: BTreeMap::new(),
+            tags: BTreeMap::new(),
+            timed_out: BTreeSet::new(),
+            aborted: BTreeSet::new(),
+            abort_factor: None,
+            abort_window: DEFAULT_ABORT_WINDOW,
+            stage_timeout: None,
+            per_stage_warmup: Duration::ZERO,
+            diff_tolerance: DEFAULT_DIFF_TOLERANCE,
+            color: true,
+            on_stage_finish: None,
             current_stage: None,
         }
     }
@@ -276,323 +1809,1951 @@ impl StagedBenchmark {
     ///
     /// Will panic if a stage with the same name already exists
     pub fn start(&mut self, name: &str) {
-        self.current_stage = Some(name.to_owned());
-        println!("{}", format!("!!! stage started: {} ", name).black());
-        let benchmark = Benchmark::new0();
-        assert!(
-            self.benchmarks.insert(name.to_owned(), benchmark).is_none(),
-            "Benchmark stage {} already exists",
-            name
-        );
+        self.start_tagged(name, BTreeMap::new());
     }
 
-    /// Finish benchmark stage
+    /// Start benchmark stage with metadata tags (e.g. input size)
     ///
-    /// # Panics
+    /// Tags are included into JSON and CSV results
     ///
-    /// Will panic if a specified stage was not started
-    pub fn finish(&mut self, name: &str, iterations: u32, errors: u32) {
-        let benchmark = self
-            .benchmarks
-            .get_mut(name)
-            .unwrap_or_else(|| panic!("Benchmark stage {} not found", name));
-        benchmark.finish(Some(iterations), Some(errors));
-        println!(
-            "{}",
-            format!(
-                "*** stage completed: {} ({} iters, {:.3} secs)",
-                name,
-                format_number!(iterations),
-                benchmark.elapsed.unwrap().as_secs_f64()
-            )
-            .black()
-        );
-    }
-
-    /// Finish current (last started) benchmark stage
     /// # Panics
     ///
-    /// Will panic if no active benchmark stage
-    pub fn finish_current(&mut self, iterations: u32, errors: u32) {
-        let current_stage = self
-            .current_stage
-            .take()
-            .expect("No active benchmark stage");
-        self.finish(&current_stage, iterations, errors);
+    /// Will panic if a stage with the same name already exists
+    pub fn start_tagged(&mut self, name: &str, tags: BTreeMap<String, String>) {
+        if let Err(e) = self.try_start_tagged(name, tags) {
+            panic!("{}", e);
+        }
     }
 
-    /// Reset staged benchmark
-    pub fn reset(&mut self) {
-        self.benchmarks.clear();
+    /// Start benchmark stage, returning an error instead of panicking
+    ///
+    /// # Errors
+    ///
+    /// Will return [`StageError::AlreadyExists`] if a stage with the same name already exists
+    pub fn try_start(&mut self, name: &str) -> Result<(), StageError> {
+        self.try_start_tagged(name, BTreeMap::new())
     }
 
-    fn _result_table_for(&self, eta: Option<&str>) -> Table {
-        let mut have_errs = false;
-        let mut results: Vec<(String, BenchmarkResult)> = Vec::new();
+    /// Start benchmark stage with metadata tags, returning an error instead of panicking
+    ///
+    /// # Errors
+    ///
+    /// Will return [`StageError::AlreadyExists`] if a stage with the same name already exists
+    pub fn try_start_tagged(
+        &mut self,
+        name: &str,
+        tags: BTreeMap<String, String>,
+    ) -> Result<(), StageError> {
+        if self.benchmarks.contains_key(name) {
+            return Err(StageError::AlreadyExists(name.to_owned()));
+        }
+        init_color();
+        self.current_stage = Some(name.to_owned());
+        let msg = format!("!!! stage started: {} ", name).black().to_string();
+        println!("{}", self.paint(msg));
+        self.benchmarks.insert(name.to_owned(), Benchmark::new0());
+        self.tags.insert(name.to_owned(), tags);
+        Ok(())
+    }
+
+    /// Enable or disable colored output for this benchmark (enabled by default)
+    pub fn set_color(&mut self, color: bool) {
+        self.color = color;
+    }
+
+    /// Set a callback, which is called when a stage is finished, with the stage name and result
+    ///
+    /// Only one callback is kept, a new one replaces the previous
+    pub fn on_stage_finish(&mut self, cb: StageFinishCallback) {
+        self.on_stage_finish = Some(cb);
+    }
+
+    /// Remove the stage completion callback
+    pub fn clear_stage_finish_callback(&mut self) {
+        self.on_stage_finish = None;
+    }
+
+    /// Set the relative speed difference (e.g. 0.02 = 2%), below which the diff is not displayed
+    ///
+    /// The default is 0.0001 (0.01%)
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the tolerance is not in 0.0..1.0 range
+    pub fn set_diff_tolerance(&mut self, fraction: f64) {
+        assert!(
+            (0.0..1.0).contains(&fraction),
+            "diff tolerance must be in 0.0..1.0 range"
+        );
+        self.diff_tolerance = fraction;
+    }
+
+    /// Set the maximum duration of a single stage, used by staged benchmark macros
+    ///
+    /// If a stage exceeds the timeout, it is stopped, the completed iterations are recorded and
+    /// the stage is marked as timed out
+    pub fn set_stage_timeout(&mut self, timeout: Duration) {
+        self.stage_timeout = Some(timeout);
+    }
+
+    /// Clear the stage timeout (stages run all the iterations)
+    pub fn clear_stage_timeout(&mut self) {
+        self.stage_timeout = None;
+    }
+
+    /// Get the stage timeout
+    pub fn stage_timeout(&self) -> Option<Duration> {
+        self.stage_timeout
+    }
+
+    /// Set the per-stage warmup duration: before each stage is started, the staged benchmark
+    /// macros run the stage code (untimed) for this duration. The default is zero (no warmup)
+    pub fn set_per_stage_warmup(&mut self, d: Duration) {
+        self.per_stage_warmup = d;
+    }
+
+    /// Get the per-stage warmup duration
+    pub fn per_stage_warmup(&self) -> Duration {
+        self.per_stage_warmup
+    }
+
+    /// Mark a stage as timed out
+    pub fn mark_timed_out(&mut self, name: &str) {
+        self.timed_out.insert(name.to_owned());
+    }
+
+    /// Check if a stage has been timed out
+    pub fn stage_timed_out(&self, name: &str) -> bool {
+        self.timed_out.contains(name)
+    }
+
+    /// Abort stages which are clearly slower than the fastest finished one
+    ///
+    /// The staged benchmark macros measure the speed of the sampling window (the first 1% of stage
+    /// iterations by default) and abort the stage if the speed is lower than the best stage speed
+    /// divided by the factor.
+    ///
+    /// Aborted stages keep the completed iterations and are marked as "aborted (slow)". Disabled
+    /// by default.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the factor is not positive
+    pub fn set_abort_if_slower_than(&mut self, factor: f64) {
+        assert!(factor > 0.0, "abort factor must be positive");
+        self.abort_factor = Some(factor);
+    }
+
+    /// Disable aborting slow stages
+    pub fn clear_abort_if_slower_than(&mut self) {
+        self.abort_factor = None;
+    }
+
+    /// Set the sampling window for [`StagedBenchmark::set_abort_if_slower_than`], as a fraction
+    /// of stage iterations (the default is 0.01)
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the fraction is not in 0.0..=1.0 range or is zero
+    pub fn set_abort_window(&mut self, fraction: f64) {
+        assert!(
+            fraction > 0.0 && fraction <= 1.0,
+            "abort window must be in 0.0..=1.0 range and non-zero"
+        );
+        self.abort_window = fraction;
+    }
+
+    #[allow(clippy::cast_sign_loss)]
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_precision_loss)]
+    /// Get the sampling window (iterations) and the minimal speed (iters/s) for a new stage,
+    /// used by macros
+    ///
+    /// Returns None if aborting is disabled or there are no finished (not aborted) stages yet
+    pub fn abort_check(&self, iterations: u64) -> Option<(u64, f64)> {
+        let factor = self.abort_factor?;
+        let best_speed = self
+            .benchmarks
+            .iter()
+            .filter(|(stage, _)| !self.aborted.contains(*stage))
+            .map(|(_, b)| b.result0().speed)
+            .max()
+            .filter(|speed| *speed > 0)?;
+        let window = ((iterations as f64 * self.abort_window) as u64).max(1);
+        Some((window, best_speed as f64 / factor))
+    }
+
+    /// Mark a stage as aborted as a slow one
+    pub fn mark_aborted(&mut self, name: &str) {
+        self.aborted.insert(name.to_owned());
+    }
+
+    /// Check if a stage has been aborted as a slow one
+    pub fn stage_aborted(&self, name: &str) -> bool {
+        self.aborted.contains(name)
+    }
+
+    /// Get results of all stages, mapped by stage names
+    pub fn results(&self) -> BTreeMap<String, BenchmarkResult> {
+        self.benchmarks
+            .iter()
+            .map(|(stage, benchmark)| (stage.clone(), benchmark.result0()))
+            .collect()
+    }
+
+    /// Get metadata tags of a stage
+    pub fn stage_tags(&self, name: &str) -> Option<&BTreeMap<String, String>> {
+        self.tags.get(name)
+    }
+
+    /// Finish benchmark stage
+    ///
+    /// # Panics
+    ///
+    /// Will panic if a specified stage was not started
+    pub fn finish(&mut self, name: &str, iterations: u64, errors: u64) {
+        self.finish_with_first_error(name, iterations, errors, None);
+    }
+
+    /// Finish benchmark stage, specifying the iteration index of the first error
+    ///
+    /// # Panics
+    ///
+    /// Will panic if a specified stage was not started
+    pub fn finish_with_first_error(
+        &mut self,
+        name: &str,
+        iterations: u64,
+        errors: u64,
+        first_error_iter: Option<u64>,
+    ) {
+        let benchmark = self
+            .benchmarks
+            .get_mut(name)
+            .unwrap_or_else(|| panic!("Benchmark stage {} not found", name));
+        benchmark.finish_with_first_error(Some(iterations), Some(errors), first_error_iter);
+        let msg = if iterations == 0 {
+            format!("*** stage completed: {} ({})", name, NOTHING_MEASURED)
+        } else {
+            format!(
+                "*** stage completed: {} ({} iters, {:.3} secs{})",
+                name,
+                format_number!(iterations),
+                benchmark.elapsed.unwrap().as_secs_f64(),
+                first_error_iter
+                    .map_or_else(String::new, |i| format!(", first error at iteration {}", i))
+            )
+        }
+        .black()
+        .to_string();
+        let result = benchmark.result0();
+        println!("{}", self.paint(msg));
+        if let Some(ref mut cb) = self.on_stage_finish {
+            cb(name, &result);
+        }
+    }
+
+    /// Finish benchmark stage, specifying the number of items processed per iteration
+    ///
+    /// If any stage has more than one item per iteration, the result table gets items/s column
+    ///
+    /// # Panics
+    ///
+    /// Will panic if a specified stage was not started
+    pub fn finish_weighted(
+        &mut self,
+        name: &str,
+        iterations: u64,
+        items_per_iter: u64,
+        errors: u64,
+    ) {
+        if let Some(benchmark) = self.benchmarks.get_mut(name) {
+            benchmark.items_per_iter = Some(items_per_iter);
+        }
+        self.finish(name, iterations, errors);
+    }
+
+    /// Finish benchmark stage, specifying per-iteration durations
+    ///
+    /// # Panics
+    ///
+    /// Will panic if a specified stage was not started
+    pub fn finish_sampled(
+        &mut self,
+        name: &str,
+        iterations: u64,
+        errors: u64,
+        samples: Vec<Duration>,
+    ) {
+        if let Some(benchmark) = self.benchmarks.get_mut(name) {
+            benchmark.samples = Some(samples);
+        }
+        self.finish(name, iterations, errors);
+    }
+
+    /// Get a percentile (0.0 - 100.0) of per-iteration durations of a stage
+    ///
+    /// Returns None if the stage is not found or has no samples recorded
+    pub fn stage_percentile(&self, name: &str, p: f64) -> Option<Duration> {
+        self.benchmarks.get(name)?.percentile(p)
+    }
+
+    /// Finish current (last started) benchmark stage
+    /// # Panics
+    ///
+    /// Will panic if no active benchmark stage
+    pub fn finish_current(&mut self, iterations: u64, errors: u64) {
+        let current_stage = self
+            .current_stage
+            .take()
+            .expect("No active benchmark stage");
+        self.finish(&current_stage, iterations, errors);
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    /// Get the geometric mean of all stage speeds (stages with zero speed are skipped)
+    pub fn geomean_speed(&self) -> f64 {
+        geomean(self.benchmarks.values().map(|b| b.result0().speed as f64))
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    /// Get the geometric mean of stage speed ratios against the reference stage
+    ///
+    /// The reference stage itself and stages with zero speed are skipped. Returns 0.0 if the
+    /// reference stage is not found, has zero speed or there are no other stages
+    pub fn geomean_ratio_vs(&self, reference_stage: &str) -> f64 {
+        let Some(reference) = self.benchmarks.get(reference_stage) else {
+            return 0.0;
+        };
+        let reference_speed = reference.result0().speed;
+        if reference_speed == 0 {
+            return 0.0;
+        }
+        geomean(
+            self.benchmarks
+                .iter()
+                .filter(|(stage, _)| *stage != reference_stage)
+                .map(|(_, b)| b.result0().speed as f64 / reference_speed as f64),
+        )
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    /// Get the speed ratio of two stages (faster / baseline)
+    ///
+    /// Returns None if any of the stages is not found or the baseline speed is zero
+    pub fn speedup(&self, faster: &str, baseline: &str) -> Option<f64> {
+        let faster_speed = self.benchmarks.get(faster)?.result0().speed;
+        let baseline_speed = self.benchmarks.get(baseline)?.result0().speed;
+        if baseline_speed == 0 {
+            return None;
+        }
+        Some(faster_speed as f64 / baseline_speed as f64)
+    }
+
+    /// Get the speed ratio of two stages as a string, e.g. "2.34x faster" or "1.50x slower"
+    ///
+    /// Returns None if the ratio can not be calculated or the first stage speed is zero
+    pub fn speedup_str(&self, faster: &str, baseline: &str) -> Option<String> {
+        let ratio = self.speedup(faster, baseline)?;
+        if ratio >= 1.0 {
+            Some(format!("{:.2}x faster", ratio))
+        } else if ratio > 0.0 {
+            Some(format!("{:.2}x slower", 1.0 / ratio))
+        } else {
+            None
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    /// Get speed ratios of all stages against the reference stage (the reference stage itself
+    /// has 1.0)
+    ///
+    /// Returns an empty vector if the reference stage is not found or its speed is zero
+    pub fn speedup_distribution(&self, reference: &str) -> Vec<(String, f64)> {
+        let Some(reference_speed) = self
+            .benchmarks
+            .get(reference)
+            .map(|b| b.result0().speed)
+            .filter(|speed| *speed > 0)
+        else {
+            return Vec::new();
+        };
+        self.benchmarks
+            .iter()
+            .map(|(stage, b)| {
+                let ratio = if stage == reference {
+                    1.0
+                } else {
+                    b.result0().speed as f64 / reference_speed as f64
+                };
+                (stage.clone(), ratio)
+            })
+            .collect()
+    }
+
+    #[allow(clippy::manual_is_multiple_of)]
+    /// Get (min, median, max) of stage speed ratios against the reference stage, see
+    /// [`StagedBenchmark::speedup_distribution`]
+    ///
+    /// Returns None if the reference stage is not found or its speed is zero
+    pub fn speedup_summary(&self, reference: &str) -> Option<(f64, f64, f64)> {
+        let mut ratios: Vec<f64> = self
+            .speedup_distribution(reference)
+            .into_iter()
+            .map(|(_, ratio)| ratio)
+            .collect();
+        if ratios.is_empty() {
+            return None;
+        }
+        ratios.sort_by(f64::total_cmp);
+        let mid = ratios.len() / 2;
+        let median = if ratios.len() % 2 == 0 {
+            (ratios[mid - 1] + ratios[mid]) / 2.0
+        } else {
+            ratios[mid]
+        };
+        Some((ratios[0], median, ratios[ratios.len() - 1]))
+    }
+
+    /// Reset staged benchmark
+    ///
+    /// The results are cleared, the settings (stage timeout etc.) are kept
+    pub fn reset(&mut self) {
+        self.benchmarks.clear();
+        self.tags.clear();
+        self.timed_out.clear();
+        self.aborted.clear();
+    }
+
+    fn paint(&self, s: String) -> String {
+        if self.color {
+            s
+        } else {
+            strip_colors(&s)
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn _result_rows(
+        &self,
+        opts: &StagedBenchmarkOptions,
+        eta: Option<&str>,
+        ranked: bool,
+    ) -> ResultRows {
+        let mut results: Vec<(String, BenchmarkResult)> = self
+            .benchmarks
+            .iter()
+            .map(|(stage, benchmark)| (stage.clone(), benchmark.result0()))
+            .collect();
+        if ranked {
+            results.sort_by_key(|(_, r)| std::cmp::Reverse(r.speed));
+        }
+        let mut rows = ResultRows::new("stage", &results, opts);
+        let have_items = self
+            .benchmarks
+            .values()
+            .any(|b| b.items_per_iter.is_some_and(|items| items > 1));
+        if have_items {
+            rows.push_column(
+                "items/s",
+                results.iter().map(|(_, result)| {
+                    format_number!(result.items_per_sec.unwrap_or(result.speed)).yellow()
+                }),
+            );
+        }
+        let have_samples = self.benchmarks.values().any(|b| b.samples().is_some());
+        if have_samples {
+            for (title, p) in [("p50", 50.0), ("p99", 99.0)] {
+                rows.push_column(
+                    title,
+                    results.iter().map(|(stage, _)| {
+                        self.benchmarks[stage]
+                            .percentile(p)
+                            .map_or_else(|| "-".normal(), |d| format_duration_scaled(d).cyan())
+                    }),
+                );
+            }
+        }
+        if !self.timed_out.is_empty() || !self.aborted.is_empty() {
+            rows.push_column(
+                "status",
+                results.iter().map(|(stage, _)| {
+                    if self.timed_out.contains(stage) {
+                        "timeout".red()
+                    } else if self.aborted.contains(stage) {
+                        "aborted (slow)".red()
+                    } else {
+                        "".normal()
+                    }
+                }),
+            );
+        }
+        if let Some(eta) = eta {
+            let eta_speed = self.benchmarks.get(eta).unwrap().result0().speed;
+            rows.push_column(
+                "diff.s",
+                results.iter().map(|(_, result)| {
+                    format_speed_diff(result.speed, eta_speed, self.diff_tolerance)
+                }),
+            );
+        }
+        if ranked {
+            let fastest = results.first().map_or(0, |(_, r)| r.speed);
+            rows.push_column(
+                "rel",
+                results.iter().map(|(_, result)| {
+                    if fastest > 0 {
+                        format!("{:.2} %", result.speed as f64 / fastest as f64 * 100.0).yellow()
+                    } else {
+                        "".normal()
+                    }
+                }),
+            );
+        }
+        rows
+    }
+
+    /// Get the fastest stage (by speed), the first one in name order is returned for ties
+    pub fn fastest(&self) -> Option<(String, BenchmarkResult)> {
+        self.pick_stage(|r, best| r.speed > best.speed)
+    }
+
+    /// Get the slowest stage (by speed), the first one in name order is returned for ties
+    pub fn slowest(&self) -> Option<(String, BenchmarkResult)> {
+        self.pick_stage(|r, best| r.speed < best.speed)
+    }
+
+    /// Get the longest running stage (by elapsed time), the first one in name order is returned
+    /// for ties
+    pub fn longest_running(&self) -> Option<(String, BenchmarkResult)> {
+        self.pick_stage(|r, best| r.elapsed > best.elapsed)
+    }
+
+    #[allow(clippy::unnecessary_map_or)]
+    fn pick_stage(
+        &self,
+        better: impl Fn(&BenchmarkResult, &BenchmarkResult) -> bool,
+    ) -> Option<(String, BenchmarkResult)> {
+        let mut picked: Option<(String, BenchmarkResult)> = None;
         for (stage, benchmark) in &self.benchmarks {
             let result = benchmark.result0();
+            if picked
+                .as_ref()
+                .map_or(true, |(_, best)| better(&result, best))
+            {
+                picked = Some((stage.clone(), result));
+            }
+        }
+        picked
+    }
+
+    /// Check if any stage had errors
+    pub fn has_errors(&self) -> bool {
+        self.benchmarks.values().any(|b| b.result0().errors > 0)
+    }
+
+    /// Get the total number of errors of all stages
+    pub fn total_errors(&self) -> u64 {
+        self.benchmarks.values().map(|b| b.result0().errors).sum()
+    }
+
+    /// Print the result table and terminate the process with exit code 1 if any stage had
+    /// errors, does nothing otherwise
+    ///
+    /// Intended to be called at the end of a benchmark binary, e.g. to fail CI jobs. Note that the
+    /// process is terminated immediately, destructors are not run
+    pub fn exit_on_error(&self) {
+        if self.has_errors() {
+            self.print();
+            eprintln!(
+                "staged benchmark finished with {} error(s)",
+                self.total_errors()
+            );
+            std::process::exit(1);
+        }
+    }
+
+    /// Get the total elapsed time of all stages
+    pub fn total_elapsed(&self) -> Duration {
+        self.benchmarks.values().map(|b| b.result0().elapsed).sum()
+    }
+
+    /// Get the result table for staged benchmark
+    pub fn result_table(&self) -> Table {
+        self._result_rows(&StagedBenchmarkOptions::default(), None, false)
+            .table(self.color, false)
+    }
+
+    /// Get the result table for staged benchmark, specifying the reference stage
+    pub fn result_table_for(&self, eta: &str) -> Table {
+        self._result_rows(&StagedBenchmarkOptions::default(), Some(eta), false)
+            .table(self.color, false)
+    }
+
+    /// Get the result table for staged benchmark with custom columns, optionally specifying
+    /// the reference stage
+    pub fn result_table_with(&self, opts: &StagedBenchmarkOptions, eta: Option<&str>) -> Table {
+        self._result_rows(opts, eta, false).table(self.color, false)
+    }
+
+    /// Get a table comparing stage speeds with another (baseline) staged benchmark
+    ///
+    /// Stages are matched by name, the diff column contains the speed change relatively to the
+    /// baseline. Stages present in one run only have a dash in the other run speed column
+    pub fn diff_table(&self, other: &StagedBenchmark) -> Table {
+        let stages: BTreeSet<&String> = self
+            .benchmarks
+            .keys()
+            .chain(other.benchmarks.keys())
+            .collect();
+        let mut table = ctable(
+            Some(vec!["stage", "base iters/s", "iters/s", "diff"]),
+            !self.color,
+        );
+        for stage in stages {
+            let base = other.benchmarks.get(stage).map(|b| b.result0().speed);
+            let current = self.benchmarks.get(stage).map(|b| b.result0().speed);
+            let speed_cell = |speed: Option<u64>| {
+                speed.map_or_else(|| cell!("-"), |v| cell!(format_number!(v).yellow()))
+            };
+            table.add_row(prettytable::Row::new(vec![
+                cell!(stage),
+                speed_cell(base),
+                speed_cell(current),
+                cell!(match (current, base) {
+                    (Some(c), Some(b)) => format_speed_diff(c, b, self.diff_tolerance),
+                    _ => "".normal(),
+                }),
+            ]));
+        }
+        if !self.color {
+            strip_table_colors(&mut table);
+        }
+        table
+    }
+
+    /// Get the result table for staged benchmark, sorted by speed (fastest first)
+    ///
+    /// The "rel" column contains stage speed relatively to the fastest one
+    pub fn result_table_ranked(&self) -> Table {
+        self._result_rows(&StagedBenchmarkOptions::default(), None, true)
+            .table(self.color, false)
+    }
+
+    #[cfg(feature = "serde")]
+    /// Get staged benchmark results as a JSON array of stage objects
+    ///
+    /// # Panics
+    ///
+    /// Should not panic
+    pub fn results_json(&self) -> String {
+        let results: Vec<StageResult> = self
+            .benchmarks
+            .iter()
+            .map(|(stage, benchmark)| StageResult {
+                stage,
+                tags: &self.tags[stage],
+                result: benchmark.result0(),
+            })
+            .collect();
+        serde_json::to_string(&results).unwrap()
+    }
+
+    #[cfg(feature = "serde")]
+    /// Load staged benchmark from JSON, previously exported with
+    /// [`StagedBenchmark::results_json`]
+    ///
+    /// Stages are reconstructed with their elapsed time, iterations, errors and tags, e.g. to be
+    /// used as a base for [`StagedBenchmark::diff_table`]
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the JSON is invalid
+    pub fn from_json(s: &str) -> Result<StagedBenchmark, serde_json::Error> {
+        let loaded: Vec<LoadedStageResult> = serde_json::from_str(s)?;
+        let mut staged = StagedBenchmark::new();
+        for stage in loaded {
+            let mut benchmark = Benchmark::new0();
+            benchmark.finish_with_first_error(
+                Some(stage.result.iterations),
+                Some(stage.result.errors),
+                stage.result.first_error,
+            );
+            benchmark.set_elapsed(stage.result.elapsed);
+            staged.tags.insert(stage.stage.clone(), stage.tags);
+            staged.benchmarks.insert(stage.stage, benchmark);
+        }
+        Ok(staged)
+    }
+
+    #[cfg(feature = "serde")]
+    /// Load staged benchmark from a JSON file, see [`StagedBenchmark::from_json`]
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the file can not be read or its contents is not valid JSON
+    pub fn load_json(path: &std::path::Path) -> io::Result<StagedBenchmark> {
+        Ok(Self::from_json(&std::fs::read_to_string(path)?)?)
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    /// Get staged benchmark results as CSV (no coloring)
+    ///
+    /// The error_rate column is included only if any stage has errors. Stage tags are included
+    /// as additional columns (one per tag name), empty for stages which have no such tag
+    pub fn result_csv(&self) -> String {
+        let results: Vec<(&String, BenchmarkResult)> = self
+            .benchmarks
+            .iter()
+            .map(|(stage, benchmark)| (stage, benchmark.result0()))
+            .collect();
+        let have_errs = results.iter().any(|(_, r)| r.errors > 0);
+        let tag_names: BTreeSet<&String> = self.tags.values().flat_map(BTreeMap::keys).collect();
+        let mut csv = String::from("stage,");
+        for tag in &tag_names {
+            csv.push_str(&csv_field(tag));
+            csv.push(',');
+        }
+        csv.push_str("iterations,errors,");
+        if have_errs {
+            csv.push_str("error_rate,");
+        }
+        csv.push_str("elapsed_secs,speed\n");
+        for (stage, result) in results {
+            csv.push_str(&csv_field(stage));
+            let tags = &self.tags[stage];
+            for tag in &tag_names {
+                csv.push(',');
+                if let Some(value) = tags.get(*tag) {
+                    csv.push_str(&csv_field(value));
+                }
+            }
+            csv.push_str(&format!(",{},{},", result.iterations, result.errors));
+            if have_errs {
+                csv.push_str(&format!("{:.4},", result.error_rate()));
+            }
+            csv.push_str(&format!(
+                "{},{}\n",
+                result.elapsed.as_secs_f64(),
+                result.speed
+            ));
+        }
+        csv
+    }
+
+    /// Get staged benchmark results as a GitHub-flavored markdown table (no coloring)
+    pub fn result_markdown(&self) -> String {
+        let ResultRows { header, rows, .. } =
+            self._result_rows(&StagedBenchmarkOptions::default(), None, false);
+        let mut md = format!("| {} |\n|", header.join(" | "));
+        for (i, _) in header.iter().enumerate() {
+            md.push_str(if i == 0 { " --- |" } else { " ---: |" });
+        }
+        md.push('\n');
+        for row in rows {
+            let cells: Vec<String> = row.iter().map(|v| v.replace('|', "\\|")).collect();
+            md.push_str(&format!("| {} |\n", cells.join(" | ")));
+        }
+        md
+    }
+
+    /// Get staged benchmark results as a standalone HTML document (inline styles only)
+    pub fn result_html(&self) -> String {
+        let ResultRows { header, rows, .. } =
+            self._result_rows(&StagedBenchmarkOptions::default(), None, false);
+        let mut html = String::from(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+            <title>Benchmark results</title>\n</head>\n<body>\n\
+            <table style=\"border-collapse:collapse;font-family:monospace\">\n<tr>",
+        );
+        for title in &header {
+            html.push_str(&format!(
+                "<th style=\"padding:2px 8px;border-bottom:1px solid #888\">{}</th>",
+                html_escape(title)
+            ));
+        }
+        html.push_str("</tr>\n");
+        for row in rows {
+            html.push_str("<tr>");
+            for (i, value) in row.iter().enumerate() {
+                let style = match header[i] {
+                    "stage" => "padding:2px 8px",
+                    "succs" => "padding:2px 8px;text-align:right;color:green",
+                    "errs" | "err.rate" | "status" => "padding:2px 8px;text-align:right;color:red",
+                    _ => "padding:2px 8px;text-align:right",
+                };
+                html.push_str(&format!(
+                    "<td style=\"{}\">{}</td>",
+                    style,
+                    html_escape(value)
+                ));
+            }
+            html.push_str("</tr>\n");
+        }
+        html.push_str("</table>\n</body>\n</html>\n");
+        html
+    }
+
+    /// Print the result table
+    pub fn print(&self) {
+        self.print_to(&mut io::stdout())
+            .expect("failed printing to stdout");
+    }
+
+    /// Print the result table, specifying the reference stage
+    pub fn print_for(&self, eta: &str) {
+        self.print_for_to(&mut io::stdout(), eta)
+            .expect("failed printing to stdout");
+    }
+
+    /// Print the result table, sorted by speed (fastest first)
+    pub fn print_ranked(&self) {
+        self.print_ranked_to(&mut io::stdout())
+            .expect("failed printing to stdout");
+    }
+
+    /// Print the result table to the specified writer
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the writer fails
+    pub fn print_to(&self, w: &mut dyn Write) -> io::Result<()> {
+        init_color();
+        let w = &mut ColorWriter::new(w, self.color);
+        writeln!(w, "{}", result_separator!())?;
+        self._result_rows(&StagedBenchmarkOptions::default(), None, false)
+            .table(self.color, true)
+            .print(w)?;
+        Ok(())
+    }
+
+    #[allow(clippy::cast_sign_loss)]
+    #[allow(clippy::cast_possible_truncation)]
+    /// Print the result table to the specified writer, specifying the reference stage
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the writer fails
+    pub fn print_for_to(&self, w: &mut dyn Write, eta: &str) -> io::Result<()> {
+        init_color();
+        let w = &mut ColorWriter::new(w, self.color);
+        writeln!(w, "{}", result_separator!())?;
+        self._result_rows(&StagedBenchmarkOptions::default(), Some(eta), false)
+            .table(self.color, true)
+            .print(w)?;
+        let ratio = self.geomean_ratio_vs(eta);
+        writeln!(
+            w,
+            "geomean: {} iters/s{}",
+            format_number!(self.geomean_speed().round() as u64).yellow(),
+            if ratio > 0.0 {
+                format!(", {:.3}x vs {}", ratio, eta)
+            } else {
+                String::new()
+            }
+        )?;
+        Ok(())
+    }
+
+    /// Print the result table to the specified writer, sorted by speed (fastest first)
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the writer fails
+    pub fn print_ranked_to(&self, w: &mut dyn Write) -> io::Result<()> {
+        init_color();
+        let w = &mut ColorWriter::new(w, self.color);
+        writeln!(w, "{}", result_separator!())?;
+        self._result_rows(&StagedBenchmarkOptions::default(), None, true)
+            .table(self.color, true)
+            .print(w)?;
+        Ok(())
+    }
+}
+
+/// Time source for benchmarks
+pub trait Clock: Send {
+    /// Get the current time
+    fn now(&self) -> Instant;
+}
+
+/// The default clock, uses [`Instant::now`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline]
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+const SPARKLINE_BUCKETS: usize = 40;
+
+/// Speed stats, derived from per-iteration samples
+#[derive(Clone, Copy)]
+struct SampleStats {
+    median_speed: Option<u64>,
+    ci95: Option<(u64, u64)>,
+}
+
+/// Simple benchmark or a stage
+pub struct Benchmark {
+    started: Instant,
+    iterations: u64,
+    set_iterations: u64,
+    errors: u64,
+    elapsed: Option<Duration>,
+    first_error: Option<u64>,
+    label: Option<String>,
+    bytes_per_iter: Option<u64>,
+    items_per_iter: Option<u64>,
+    elements_per_iter: Option<u64>,
+    samples: Option<Vec<Duration>>,
+    sample_stats: Option<SampleStats>,
+    tuning_elapsed: Duration,
+    clock: Box<dyn Clock>,
+    color: bool,
+}
+
+impl Default for Benchmark {
+    fn default() -> Self {
+        Self::new0()
+    }
+}
+
+impl fmt::Display for Benchmark {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            self.to_string_for(Some(self.iterations), Some(self.errors))
+        )
+    }
+}
+
+impl Benchmark {
+    /// Create simple benchmark with unknown number of iterations
+    pub fn new0() -> Self {
+        Self {
+            started: Instant::now(),
+            iterations: 0,
+            set_iterations: 0,
+            errors: 0,
+            elapsed: None,
+            first_error: None,
+            label: None,
+            bytes_per_iter: None,
+            items_per_iter: None,
+            elements_per_iter: None,
+            samples: None,
+            sample_stats: None,
+            tuning_elapsed: Duration::ZERO,
+            clock: Box::new(SystemClock),
+            color: true,
+        }
+    }
+
+    /// Create simple benchmark with pre-defined number of iterations
+    pub fn new(iterations: u64) -> Self {
+        Self {
+            started: Instant::now(),
+            iterations,
+            set_iterations: iterations,
+            errors: 0,
+            elapsed: None,
+            first_error: None,
+            label: None,
+            bytes_per_iter: None,
+            items_per_iter: None,
+            elements_per_iter: None,
+            samples: None,
+            sample_stats: None,
+            tuning_elapsed: Duration::ZERO,
+            clock: Box::new(SystemClock),
+            color: true,
+        }
+    }
+
+    /// Create simple benchmark with unknown number of iterations
+    ///
+    /// The same as [`Benchmark::new0`]: reset does not restore any pre-defined number of
+    /// iterations, so the number must be specified at finish / print or counted with
+    /// [`Benchmark::increment`]
+    pub fn new_dynamic() -> Self {
+        Self::new0()
+    }
+
+    /// Create simple benchmark with unknown number of iterations, which uses the custom clock
+    /// to get the current time
+    pub fn with_clock(clock: Box<dyn Clock>) -> Self {
+        let mut benchmark = Self::new0();
+        benchmark.started = clock.now();
+        benchmark.clock = clock;
+        benchmark
+    }
+
+    fn since_started(&self) -> Duration {
+        self.clock.now().saturating_duration_since(self.started)
+    }
+
+    /// Create simple benchmark with unknown number of iterations, which records per-iteration
+    /// durations
+    pub fn new_sampling() -> Self {
+        Self {
+            samples: Some(Vec::new()),
+            ..Self::new0()
+        }
+    }
+
+    /// Time a single closure call, returns the closure result and the elapsed time
+    ///
+    /// The result is passed through black box. No global state is used
+    pub fn time<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+        let started = Instant::now();
+        let result = std::hint::black_box(f());
+        (result, started.elapsed())
+    }
+
+    /// Set a label, which is displayed in the results
+    pub fn with_label(mut self, label: &str) -> Self {
+        self.set_label(Some(label));
+        self
+    }
+
+    /// Enable or disable colored output for this benchmark (enabled by default)
+    pub fn set_color(&mut self, color: bool) {
+        self.color = color;
+    }
+
+    /// Set or clear the benchmark label
+    pub fn set_label(&mut self, label: Option<&str>) {
+        self.label = label.map(ToOwned::to_owned);
+    }
+
+    /// Reset the benchmark timer
+    ///
+    /// The number of iterations is restored to the value, specified at creation (zero for
+    /// dynamic benchmarks), errors and the finished state are cleared. Iterations, specified
+    /// at the previous finish, are not kept.
+    pub fn reset(&mut self) {
+        self.started = self.clock.now();
+        self.elapsed = None;
+        self.iterations = self.set_iterations;
+        self.errors = 0;
+        self.first_error = None;
+        self.bytes_per_iter = None;
+        self.items_per_iter = None;
+        self.elements_per_iter = None;
+        self.tuning_elapsed = Duration::ZERO;
+        self.sample_stats = None;
+        if let Some(ref mut samples) = self.samples {
+            samples.clear();
+        }
+    }
+
+    /// Record a single iteration duration
+    ///
+    /// Ignored if the benchmark has been created without sampling
+    #[inline]
+    pub fn sample(&mut self, d: Duration) {
+        if let Some(ref mut samples) = self.samples {
+            samples.push(d);
+            self.sample_stats = None;
+        }
+    }
+
+    #[allow(clippy::cast_sign_loss)]
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_precision_loss)]
+    /// Get iters/s, implied by the trimmed mean iteration duration
+    ///
+    /// The specified fraction of the fastest and the slowest samples is dropped before the mean
+    /// is calculated. Returns None if sampling is disabled or there are no samples
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the trim fraction is not in 0.0..0.5 range
+    pub fn speed_trimmed(&self, trim_fraction: f64) -> Option<u64> {
+        assert!(
+            (0.0..0.5).contains(&trim_fraction),
+            "trim fraction must be in 0.0..0.5 range"
+        );
+        let mut sorted = self.samples.clone()?;
+        sorted.sort_unstable();
+        let trim = (sorted.len() as f64 * trim_fraction) as usize;
+        let trimmed = &sorted[trim..sorted.len() - trim];
+        if trimmed.is_empty() {
+            return None;
+        }
+        let mean = trimmed.iter().sum::<Duration>().as_nanos() / trimmed.len() as u128;
+        1_000_000_000_u128
+            .checked_div(mean)
+            .map(|speed| speed as u64)
+    }
+
+    /// Get iters/s, implied by the median iteration duration
+    ///
+    /// Returns None if sampling is disabled or there are no samples
+    pub fn median_speed(&self) -> Option<u64> {
+        self.sample_stats().median_speed
+    }
+
+    /// Get 95% confidence interval of iters/s (low, high), calculated from per-iteration samples
+    /// as mean ± 1.96 standard errors of the iteration duration
+    ///
+    /// Returns None if sampling is disabled or there are less than 2 samples
+    pub fn speed_ci95(&self) -> Option<(u64, u64)> {
+        self.sample_stats().ci95
+    }
+
+    /// Sample stats, cached at finish, calculated on demand if the benchmark is not finished
+    fn sample_stats(&self) -> SampleStats {
+        self.sample_stats
+            .unwrap_or_else(|| self.calc_sample_stats())
+    }
+
+    fn calc_sample_stats(&self) -> SampleStats {
+        SampleStats {
+            median_speed: self.calc_median_speed(),
+            ci95: self.calc_speed_ci95(),
+        }
+    }
+
+    fn calc_median_speed(&self) -> Option<u64> {
+        let mut sorted = self.samples.clone()?;
+        sorted.sort_unstable();
+        let median = percentile_of(&sorted, 50.0);
+        1_000_000_000_u128
+            .checked_div(median.as_nanos())
+            .map(|speed| speed as u64)
+    }
+
+    #[allow(clippy::cast_sign_loss)]
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_precision_loss)]
+    fn calc_speed_ci95(&self) -> Option<(u64, u64)> {
+        let samples = self.samples.as_ref()?;
+        if samples.len() < 2 {
+            return None;
+        }
+        let n = samples.len() as f64;
+        let nanos = || samples.iter().map(|d| d.as_nanos() as f64);
+        let mean = nanos().sum::<f64>() / n;
+        let variance = nanos().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0);
+        let margin = 1.96 * variance.sqrt() / n.sqrt();
+        let speed = |nanos: f64| {
+            if nanos > 0.0 {
+                (1_000_000_000.0 / nanos) as u64
+            } else {
+                u64::MAX
+            }
+        };
+        Some((speed(mean + margin), speed(mean - margin)))
+    }
+
+    /// Get recorded per-iteration durations, None if sampling is disabled
+    pub fn samples(&self) -> Option<&[Duration]> {
+        self.samples.as_deref()
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    #[allow(clippy::cast_precision_loss)]
+    /// Get a sparkline of per-iteration speed over time
+    ///
+    /// Per-iteration durations are split into up to 40 time-ordered buckets, each bucket is
+    /// displayed as a bar of its average speed. Returns None if sampling is disabled or there are
+    /// no samples
+    pub fn sparkline(&self) -> Option<String> {
+        const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+        let samples = self.samples.as_deref().filter(|s| !s.is_empty())?;
+        let buckets = samples.len().min(SPARKLINE_BUCKETS);
+        let speeds: Vec<f64> = (0..buckets)
+            .map(|i| {
+                let bucket =
+                    &samples[i * samples.len() / buckets..(i + 1) * samples.len() / buckets];
+                let elapsed: Duration = bucket.iter().sum();
+                rate_per_sec(bucket.len() as f64, elapsed.as_secs_f64()) as f64
+            })
+            .collect();
+        let min = speeds.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = speeds.iter().copied().fold(0.0, f64::max);
+        Some(
+            speeds
+                .into_iter()
+                .map(|speed| {
+                    if max > min {
+                        BARS[((speed - min) / (max - min) * 7.0).round() as usize]
+                    } else {
+                        BARS[0]
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    /// Print a sparkline of per-iteration speed over time, see [`Benchmark::sparkline`]
+    ///
+    /// Nothing is printed if sampling is disabled or there are no samples
+    pub fn print_sparkline(&self) {
+        if let Some(sparkline) = self.sparkline() {
+            init_color();
+            let line = format!("speed: {}", sparkline.yellow());
+            println!(
+                "{}",
+                if self.color {
+                    line
+                } else {
+                    strip_colors(&line)
+                }
+            );
+        }
+    }
+
+    /// Get a percentile (0.0 - 100.0) of per-iteration durations
+    ///
+    /// Returns None if sampling is disabled or there are no samples
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        let mut sorted = self.samples.clone()?;
+        if sorted.is_empty() {
+            return None;
+        }
+        sorted.sort_unstable();
+        Some(percentile_of(&sorted, p))
+    }
+
+    /// Get the elapsed time: the measured duration if finished or set externally, the time since
+    /// the benchmark start otherwise (always available)
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed.unwrap_or_else(|| self.since_started())
+    }
+
+    /// Set the elapsed time, measured externally
+    ///
+    /// The value is used for results instead of the time measured by the benchmark. Note that
+    /// finish methods overwrite the elapsed time, so call this one after
+    pub fn set_elapsed(&mut self, elapsed: Duration) {
+        self.elapsed = Some(elapsed);
+    }
+
+    /// Finish a simple benchmark
+    pub fn finish0(&mut self) {
+        self.elapsed = Some(self.since_started());
+        self.sample_stats = Some(self.calc_sample_stats());
+    }
+
+    /// Finish a simple benchmark, specifying number of iterations made
+    ///
+    /// If iterations / errors are not specified, the current values are kept: pre-defined at
+    /// creation / restored by reset or counted with increment methods
+    pub fn finish(&mut self, iterations: Option<u64>, errors: Option<u64>) {
+        self.elapsed = Some(self.since_started());
+        self.sample_stats = Some(self.calc_sample_stats());
+        if let Some(i) = iterations {
+            self.iterations = i;
+        }
+        if let Some(e) = errors {
+            self.errors = e;
+        }
+    }
+
+    /// Finish a simple benchmark, specifying number of iterations made, errors and the
+    /// iteration index of the first error
+    pub fn finish_with_first_error(
+        &mut self,
+        iterations: Option<u64>,
+        errors: Option<u64>,
+        first_error_iter: Option<u64>,
+    ) {
+        self.finish(iterations, errors);
+        self.first_error = first_error_iter;
+    }
+
+    /// Finish a simple benchmark, specifying number of iterations made and bytes processed per
+    /// iteration
+    pub fn finish_bytes(&mut self, iterations: Option<u64>, bytes_per_iter: u64) {
+        self.finish(iterations, None);
+        self.bytes_per_iter = Some(bytes_per_iter);
+    }
+
+    /// Finish a simple benchmark, specifying number of iterations made, items processed per
+    /// iteration and errors
+    ///
+    /// Items per second are reported in addition to iterations per second
+    pub fn finish_weighted(
+        &mut self,
+        iterations: Option<u64>,
+        items_per_iter: u64,
+        errors: Option<u64>,
+    ) {
+        self.finish(iterations, errors);
+        self.items_per_iter = Some(items_per_iter);
+    }
+
+    /// Set the time spent on tuning the number of iterations (auto mode), which is reported
+    /// separately and not included into speed
+    pub fn set_tuning_elapsed(&mut self, tuning_elapsed: Duration) {
+        self.tuning_elapsed = tuning_elapsed;
+    }
+
+    /// Finish a simple benchmark, specifying number of iterations made and elements (rows,
+    /// messages etc.) processed per iteration
+    pub fn finish_elements(&mut self, iterations: Option<u64>, elements_per_iter: u64) {
+        self.finish(iterations, None);
+        self.elements_per_iter = Some(elements_per_iter);
+    }
+
+    /// Print a simple benchmark result
+    pub fn print0(&self) {
+        self.print(Some(self.iterations), Some(self.errors));
+    }
+
+    /// Print a simple benchmark result in one line, without colors
+    pub fn print_oneline(&self) {
+        match &self.label {
+            Some(label) => println!("{}: {}", label, self.result0()),
+            None => println!("{}", self.result0()),
+        }
+    }
+
+    /// Print a simple benchmark result, specifying number of iterations made
+    pub fn print(&self, iterations: Option<u64>, errors: Option<u64>) {
+        self.print_to(&mut io::stdout(), iterations, errors)
+            .expect("failed printing to stdout");
+    }
+
+    /// Print a simple benchmark result to the specified writer
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the writer fails
+    pub fn print_to(
+        &self,
+        w: &mut dyn Write,
+        iterations: Option<u64>,
+        errors: Option<u64>,
+    ) -> io::Result<()> {
+        init_color();
+        writeln!(w, "{}", self.to_string_for(iterations, errors))
+    }
+
+    #[allow(clippy::cast_sign_loss)]
+    #[allow(clippy::cast_possible_truncation)]
+    /// Get a benchmark result
+    pub fn result0(&self) -> BenchmarkResult {
+        self.result(Some(self.iterations), Some(self.errors))
+    }
+
+    #[allow(clippy::cast_sign_loss)]
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_precision_loss)]
+    /// Get a benchmark result, specifying number of iterations made
+    pub fn result(&self, iterations: Option<u64>, errors: Option<u64>) -> BenchmarkResult {
+        let elapsed = self.elapsed.unwrap_or_else(|| self.since_started());
+        let it = iterations.unwrap_or(self.iterations);
+        let errs = errors.unwrap_or(self.errors);
+        let sample_stats = self.sample_stats();
+        BenchmarkResult {
+            elapsed,
+            iterations: it,
+            errors: errs,
+            speed: rate_per_sec((it - errs) as f64, elapsed.as_secs_f64()),
+            per_iter: per_iter_of(elapsed, it),
+            bytes_per_sec: self.bytes_per_iter.map(|bytes| {
+                rate_per_sec((it - errs) as f64 * bytes as f64, elapsed.as_secs_f64())
+            }),
+            items_per_sec: self.items_per_iter.map(|items| {
+                rate_per_sec((it - errs) as f64 * items as f64, elapsed.as_secs_f64())
+            }),
+            elements: self.elements_per_iter,
+            median_speed: sample_stats.median_speed,
+            first_error: self.first_error,
+            ci95: sample_stats.ci95,
+            tuning_elapsed: self.tuning_elapsed,
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    /// Get a benchmark result as a JSON object
+    ///
+    /// # Panics
+    ///
+    /// Should not panic
+    pub fn result_json(&self) -> String {
+        serde_json::to_string(&self.result0()).unwrap()
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn to_string_for(&self, iterations: Option<u64>, errors: Option<u64>) -> String {
+        let result = self.result(iterations, errors);
+        if result.iterations == 0 {
+            let s = format!(
+                "{}\n{}",
+                result_separator!(self.label.as_ref()),
+                NOTHING_MEASURED.yellow()
+            );
+            return if self.color { s } else { strip_colors(&s) };
+        }
+        let elapsed = result.elapsed.as_secs_f64();
+        let mut s = format!(
+            "{}\nIterations: {}, success: {}, errors: {}{}\n\
+            Elapsed:\n {} secs ({} msecs)\n {} iters/s\n {} per iter",
+            result_separator!(self.label.as_ref()),
+            format_number!(result.iterations).magenta(),
+            format_number!(result.iterations - result.errors).green(),
+            if result.errors > 0 {
+                format_number!(result.errors).red()
+            } else {
+                "None".normal()
+            },
             if result.errors > 0 {
-                have_errs = true;
+                format!(
+                    ", error rate: {}{}",
+                    format!("{:.2} %", (result.error_rate() * 100.0)).red(),
+                    result.first_error.map_or_else(String::new, |i| format!(
+                        ", first error at iteration {}",
+                        format_number!(i).red()
+                    ))
+                )
+            } else {
+                String::new()
+            },
+            format!("{:.3}", elapsed).blue(),
+            format!("{:.3}", elapsed * 1000.0).cyan(),
+            format_number!(result.speed).yellow(),
+            if result.speed > 0 {
+                format_duration_scaled(result.per_iter).magenta()
+            } else {
+                "n/a".normal()
             }
-            results.push((stage.clone(), result));
+        );
+        if !result.tuning_elapsed.is_zero() {
+            s += &format!(
+                "\n {} secs spent on tuning",
+                format!("{:.3}", result.tuning_elapsed.as_secs_f64()).blue()
+            );
         }
-        let mut header = vec!["stage", "iters"];
-        if have_errs {
-            header.extend(["succs", "errs", "err.rate"]);
+        if let Some(median_speed) = result.median_speed {
+            s += &format!(
+                "\n {} iters/s (median)",
+                format_number!(median_speed).yellow()
+            );
         }
-        header.extend(["secs", "msecs", "iters/s"]);
-        let eta_speed = eta.map(|v| {
-            header.push("diff.s");
-            self.benchmarks.get(v).unwrap().result0().speed
-        });
-        let mut table = ctable(Some(header), false);
-        for (stage, benchmark) in &self.benchmarks {
-            let result = benchmark.result0();
-            let elapsed = result.elapsed.as_secs_f64();
-            let mut cells = vec![
-                cell!(stage),
-                cell!(format_number!(result.iterations).magenta()),
-            ];
-            if have_errs {
-                let success = result.iterations - result.errors;
-                cells.extend([
-                    cell!(if success > 0 {
-                        format_number!(success).green()
-                    } else {
-                        <_>::default()
-                    }),
-                    cell!(if result.errors > 0 {
-                        format_number!(result.errors).red()
-                    } else {
-                        <_>::default()
-                    }),
-                    cell!(if result.errors > 0 {
-                        format!(
-                            "{:.2} %",
-                            (f64::from(result.errors) / f64::from(result.iterations) * 100.0)
-                        )
-                        .red()
-                    } else {
-                        "".normal()
-                    }),
-                ]);
-            }
-            cells.extend([
-                cell!(format!("{:.3}", elapsed).blue()),
-                cell!(format!("{:.3}", elapsed * 1000.0).cyan()),
-                cell!(format_number!(result.speed).yellow()),
-            ]);
-            if let Some(r) = eta_speed {
-                if result.speed != r {
-                    let diff = f64::from(result.speed) / f64::from(r);
-                    if !(0.9999..=1.0001).contains(&diff) {
-                        cells.push(cell!(if diff > 1.0 {
-                            format!("+{:.2} %", ((diff - 1.0) * 100.0)).green()
-                        } else {
-                            format!("-{:.2} %", ((1.0 - diff) * 100.0)).red()
-                        }));
-                    }
-                }
-            };
-            table.add_row(prettytable::Row::new(cells));
+        if let Some((low, high)) = result.speed_ci95() {
+            s += &format!(
+                "\n {} - {} iters/s (95% CI)",
+                format_number!(low).yellow(),
+                format_number!(high).yellow()
+            );
+        }
+        if let Some(items_per_sec) = result.items_per_sec {
+            s += &format!("\n {} items/s", format_number!(items_per_sec).yellow());
+        }
+        if let Some(elements_per_sec) = result.elements_per_sec() {
+            s += &format!(
+                "\n {} elements/s",
+                format_number!(elements_per_sec).yellow()
+            );
+        }
+        if let Some(bytes_per_sec) = result.bytes_per_sec {
+            s += &format!(
+                "\n {} MiB/s",
+                format!("{:.2}", bytes_per_sec as f64 / 1_048_576.0).green()
+            );
+        }
+        if self.color {
+            s
+        } else {
+            strip_colors(&s)
         }
-        table
     }
 
-    /// Get the result table for staged benchmark
-    pub fn result_table(&self) -> Table {
-        self._result_table_for(None)
+    /// Increment iterations inside benchmark
+    ///
+    /// Not required to use if the number of iterations is specified at benchmark creation or
+    /// finish / print
+    pub fn increment(&mut self) {
+        self.iterations += 1;
     }
 
-    /// Get the result table for staged benchmark, specifying the reference stage
-    pub fn result_table_for(&self, eta: &str) -> Table {
-        self._result_table_for(Some(eta))
+    /// Increment errors inside benchmark
+    ///
+    /// Not required to use if the number of errors is specified at benchmark finish / print
+    pub fn increment_errors(&mut self) {
+        self.errors += 1;
     }
+}
 
-    /// Print the result table
+/// Run a benchmark, consuming an iterator
+///
+/// Each item yielded is counted as an iteration
+pub fn benchmark_iter<I: Iterator>(iter: I) -> BenchmarkResult {
+    let mut benchmark = Benchmark::new0();
+    let mut iterations: u64 = 0;
+    for item in iter {
+        std::hint::black_box(item);
+        iterations += 1;
+    }
+    benchmark.finish(Some(iterations), None);
+    benchmark.result0()
+}
+
+/// Run a benchmark in multiple threads and get the aggregate result
+///
+/// Each thread runs the closure the specified number of times. Threads are started
+/// simultaneously, the total wall time is measured.
+///
+/// # Panics
+///
+/// Will panic if the number of threads is zero
+pub fn benchmark_parallel(
+    threads: usize,
+    iterations_per_thread: u64,
+    f: impl Fn() + Sync,
+) -> BenchmarkResult {
+    assert!(
+        threads > 0,
+        "the number of threads must be greater than zero"
+    );
+    let barrier = std::sync::Barrier::new(threads);
+    let started = std::sync::OnceLock::new();
+    std::thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                // the leader is the last thread arrived, its timestamp is the moment all are
+                // released
+                if barrier.wait().is_leader() {
+                    started.set(Instant::now()).unwrap();
+                }
+                for _ in 0..iterations_per_thread {
+                    f();
+                    std::hint::black_box(());
+                }
+            });
+        }
+    });
+    let mut benchmark = Benchmark::new0();
+    benchmark.started = started.into_inner().unwrap();
+    benchmark.finish(
+        Some(iterations_per_thread.saturating_mul(threads as u64)),
+        None,
+    );
+    benchmark.result0()
+}
+
+/// Results of a benchmark, repeated multiple times
+pub struct RepeatedResult {
+    /// iterations per run
+    pub iterations: u64,
+    /// iters/s of each run
+    pub speeds: Vec<u64>,
+    pub mean: f64,
+    /// population standard deviation
+    pub std_dev: f64,
+    pub min: u64,
+    pub max: u64,
+    /// coefficient of variation (std_dev / mean), 0.0 if the mean is zero
+    pub cv: f64,
+}
+
+impl RepeatedResult {
+    #[allow(clippy::cast_precision_loss)]
+    fn new(iterations: u64, speeds: Vec<u64>) -> Self {
+        let n = speeds.len() as f64;
+        let (mean, std_dev) = if speeds.is_empty() {
+            (0.0, 0.0)
+        } else {
+            let mean = speeds.iter().map(|v| *v as f64).sum::<f64>() / n;
+            let variance = speeds
+                .iter()
+                .map(|v| (*v as f64 - mean).powi(2))
+                .sum::<f64>()
+                / n;
+            (mean, variance.sqrt())
+        };
+        Self {
+            iterations,
+            min: speeds.iter().min().copied().unwrap_or_default(),
+            max: speeds.iter().max().copied().unwrap_or_default(),
+            cv: if mean > 0.0 { std_dev / mean } else { 0.0 },
+            speeds,
+            mean,
+            std_dev,
+        }
+    }
+
+    /// Print the results
     pub fn print(&self) {
-        println!("{}", result_separator!());
-        self.result_table().printstd();
+        self.print_to(&mut io::stdout())
+            .expect("failed printing to stdout");
     }
 
-    /// Print the result table, specifying the reference stage
-    pub fn print_for(&self, eta: &str) {
-        println!("{}", result_separator!());
-        self.result_table_for(eta).printstd();
+    #[allow(clippy::cast_sign_loss)]
+    #[allow(clippy::cast_possible_truncation)]
+    /// Print the results to the specified writer
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the writer fails
+    pub fn print_to(&self, w: &mut dyn Write) -> io::Result<()> {
+        init_color();
+        writeln!(w, "{}", result_separator!())?;
+        writeln!(
+            w,
+            "Runs: {}, iterations per run: {}",
+            format_number!(self.speeds.len()).magenta(),
+            format_number!(self.iterations).magenta()
+        )?;
+        writeln!(
+            w,
+            "Speed (iters/s):\n mean: {} ± {} (cv {})\n min: {}, max: {}",
+            format_number!(self.mean.round() as u64).yellow(),
+            format_number!(self.std_dev.round() as u64).yellow(),
+            format!("{:.2} %", self.cv * 100.0).cyan(),
+            format_number!(self.min).red(),
+            format_number!(self.max).green()
+        )
     }
 }
 
-/// Simple benchmark or a stage
-pub struct Benchmark {
-    started: Instant,
-    iterations: u32,
-    set_iterations: u32,
-    errors: u32,
-    elapsed: Option<Duration>,
+/// Run a benchmark multiple times and get speed variance across runs
+///
+/// Each run calls the closure the specified number of times
+pub fn benchmark_repeated(runs: usize, iterations: u64, mut f: impl FnMut()) -> RepeatedResult {
+    let mut speeds = Vec::with_capacity(runs);
+    for _ in 0..runs {
+        let mut benchmark = Benchmark::new(iterations);
+        for _ in 0..iterations {
+            f();
+            std::hint::black_box(());
+        }
+        benchmark.finish0();
+        speeds.push(benchmark.result0().speed);
+    }
+    RepeatedResult::new(iterations, speeds)
 }
 
-impl Default for Benchmark {
-    fn default() -> Self {
-        Self::new0()
+/// Get a combined result table of the named simple benchmarks
+///
+/// The error columns are displayed only if there are errors. The table is colored only if colors
+/// are enabled for all the named benchmarks
+pub fn named_benchmarks_table() -> Table {
+    let benchmarks = named_benchmarks();
+    let results: Vec<(String, BenchmarkResult)> = benchmarks
+        .iter()
+        .map(|(name, benchmark)| (name.clone(), benchmark.result0()))
+        .collect();
+    let color = benchmarks.values().all(|b| b.color);
+    ResultRows::new("benchmark", &results, &StagedBenchmarkOptions::default()).table(color, false)
+}
+
+/// Print the combined result table of the named simple benchmarks
+pub fn print_named_benchmarks() {
+    print_named_benchmarks_to(&mut io::stdout()).expect("failed printing to stdout");
+}
+
+/// Print the combined result table of the named simple benchmarks to the specified writer
+///
+/// # Errors
+///
+/// Will return an error if the writer fails
+pub fn print_named_benchmarks_to(w: &mut dyn Write) -> io::Result<()> {
+    init_color();
+    let color = named_benchmarks().values().all(|b| b.color);
+    let w = &mut ColorWriter::new(w, color);
+    writeln!(w, "{}", result_separator!())?;
+    named_benchmarks_table().print(w)?;
+    Ok(())
+}
+
+#[allow(clippy::cast_precision_loss)]
+/// Geometric mean of positive values, other values are skipped, returns 0.0 if empty
+fn geomean(values: impl Iterator<Item = f64>) -> f64 {
+    let (sum, count) = values
+        .filter(|v| *v > 0.0)
+        .fold((0.0, 0_usize), |(sum, count), v| (sum + v.ln(), count + 1));
+    if count == 0 {
+        0.0
+    } else {
+        (sum / count as f64).exp()
     }
 }
 
-impl fmt::Display for Benchmark {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{}",
-            self.to_string_for(Some(self.iterations), Some(self.errors))
-        )
+/// Format a percentage diff against a reference speed, empty within tolerance
+#[allow(clippy::cast_precision_loss)]
+fn format_speed_diff(speed: u64, reference: u64, tolerance: f64) -> colored::ColoredString {
+    let diff = speed as f64 / reference as f64;
+    if speed == reference || (1.0 - tolerance..=1.0 + tolerance).contains(&diff) {
+        "".normal()
+    } else if reference == 0 {
+        "n/a".normal()
+    } else if diff > 1.0 {
+        format!("+{:.2} %", ((diff - 1.0) * 100.0)).green()
+    } else {
+        format!("-{:.2} %", ((1.0 - diff) * 100.0)).red()
     }
 }
 
-impl Benchmark {
-    /// Create simple benchmark with unknown number of iterations
-    pub fn new0() -> Self {
-        Self {
-            started: Instant::now(),
-            iterations: 0,
-            set_iterations: 0,
-            errors: 0,
-            elapsed: None,
-        }
+#[allow(clippy::cast_sign_loss)]
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_precision_loss)]
+/// Calculate a per-second rate, explicitly clamped to the u64 range (zero if undefined)
+fn rate_per_sec(count: f64, secs: f64) -> u64 {
+    let rate = count / secs;
+    if rate.is_nan() {
+        0
+    } else {
+        rate.clamp(0.0, u64::MAX as f64) as u64
     }
+}
 
-    /// Create simple benchmark with pre-defined number of iterations
-    pub fn new(iterations: u32) -> Self {
+fn per_iter_of(elapsed: Duration, iterations: u64) -> Duration {
+    elapsed
+        .as_nanos()
+        .checked_div(u128::from(iterations))
+        .map_or(Duration::ZERO, |nanos| {
+            Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX))
+        })
+}
+
+/// Format a duration, choosing ns/μs/ms/s unit according to its magnitude
+fn format_duration_scaled(d: Duration) -> String {
+    let nanos = d.as_nanos();
+    if nanos < 1_000 {
+        format!("{} ns", nanos)
+    } else if nanos < 1_000_000 {
+        format!("{:.3} μs", d.as_secs_f64() * 1_000_000.0)
+    } else if nanos < 1_000_000_000 {
+        format!("{:.3} ms", d.as_secs_f64() * 1_000.0)
+    } else {
+        format!("{:.3} s", d.as_secs_f64())
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Writer wrapper which strips ANSI escape sequences if colors are disabled for an instance
+struct ColorWriter<'a> {
+    inner: &'a mut dyn Write,
+    color: bool,
+    // 0 - text, 1 - ESC received, 2 - inside a CSI sequence
+    state: u8,
+}
+
+impl<'a> ColorWriter<'a> {
+    fn new(inner: &'a mut dyn Write, color: bool) -> Self {
         Self {
-            started: Instant::now(),
-            iterations,
-            set_iterations: iterations,
-            errors: 0,
-            elapsed: None,
+            inner,
+            color,
+            state: 0,
         }
     }
+}
 
-    /// Reset the benchmark timer
-    pub fn reset(&mut self) {
-        self.started = Instant::now();
-        self.iterations = self.set_iterations;
-        self.errors = 0;
+impl Write for ColorWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.color {
+            return self.inner.write(buf);
+        }
+        let mut plain = Vec::with_capacity(buf.len());
+        for &b in buf {
+            match (self.state, b) {
+                (0, 0x1b) => self.state = 1,
+                (0, _) => plain.push(b),
+                (1, b'[') => self.state = 2,
+                (1, _) => {
+                    self.state = 0;
+                    plain.push(b);
+                }
+                (_, 0x40..=0x7e) => self.state = 0,
+                _ => {}
+            }
+        }
+        self.inner.write_all(&plain)?;
+        Ok(buf.len())
     }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
 
-    /// Finish a simple benchmark
-    pub fn finish0(&mut self) {
-        self.elapsed = Some(self.started.elapsed());
+fn strip_colors(s: &str) -> String {
+    let mut buf = Vec::with_capacity(s.len());
+    ColorWriter::new(&mut buf, false)
+        .write_all(s.as_bytes())
+        .expect("failed writing to a buffer");
+    String::from_utf8(buf).expect("invalid UTF-8 after stripping colors")
+}
+
+/// Result table header, rows and the total row (colored cells)
+struct ResultRows {
+    header: Vec<&'static str>,
+    rows: Vec<Vec<colored::ColoredString>>,
+    total: Option<Vec<colored::ColoredString>>,
+}
+
+impl ResultRows {
+    /// Create rows with the common columns (iterations, errors, elapsed, speed), the first column
+    /// contains result names
+    fn new(
+        title: &'static str,
+        results: &[(String, BenchmarkResult)],
+        opts: &StagedBenchmarkOptions,
+    ) -> Self {
+        let have_errs = opts.show_errors && results.iter().any(|(_, r)| r.errors > 0);
+        let mut header = vec![title];
+        if opts.show_iters {
+            header.push("iters");
+        }
+        if have_errs {
+            header.extend(["succs", "errs", "err.rate"]);
+        }
+        if opts.show_secs {
+            header.push("secs");
+        }
+        if opts.show_msecs {
+            header.push("msecs");
+        }
+        if opts.show_speed {
+            header.push("iters/s");
+        }
+        let cells = |name: colored::ColoredString, result: &BenchmarkResult| {
+            let elapsed = result.elapsed.as_secs_f64();
+            let mut cells = vec![name];
+            if opts.show_iters {
+                cells.push(format_number!(result.iterations).magenta());
+            }
+            if have_errs {
+                let success = result.iterations - result.errors;
+                cells.extend([
+                    if success > 0 {
+                        format_number!(success).green()
+                    } else {
+                        "".normal()
+                    },
+                    if result.errors > 0 {
+                        format_number!(result.errors).red()
+                    } else {
+                        "".normal()
+                    },
+                    if result.errors > 0 {
+                        format!("{:.2} %", (result.error_rate() * 100.0)).red()
+                    } else {
+                        "".normal()
+                    },
+                ]);
+            }
+            if opts.show_secs {
+                cells.push(format!("{:.3}", elapsed).blue());
+            }
+            if opts.show_msecs {
+                cells.push(format!("{:.3}", elapsed * 1000.0).cyan());
+            }
+            if opts.show_speed {
+                cells.push(if result.iterations == 0 {
+                    "n/a".normal()
+                } else {
+                    format_number!(result.speed).yellow()
+                });
+            }
+            cells
+        };
+        let rows = results
+            .iter()
+            .map(|(name, result)| cells(name.normal(), result))
+            .collect();
+        let total = if results.is_empty() {
+            None
+        } else {
+            let results: Vec<BenchmarkResult> = results.iter().map(|(_, r)| r.clone()).collect();
+            Some(cells(
+                "TOTAL".yellow().bold(),
+                &BenchmarkResult::merge(&results),
+            ))
+        };
+        Self {
+            header,
+            rows,
+            total,
+        }
     }
 
-    /// Finish a simple benchmark, specifying number of iterations made
-    pub fn finish(&mut self, iterations: Option<u32>, errors: Option<u32>) {
-        self.elapsed = Some(self.started.elapsed());
-        if let Some(i) = iterations {
-            self.iterations = i;
+    /// Append a column, the total row cell is left blank
+    fn push_column(
+        &mut self,
+        title: &'static str,
+        cells: impl Iterator<Item = colored::ColoredString>,
+    ) {
+        self.header.push(title);
+        for (row, cell) in self.rows.iter_mut().zip(cells) {
+            row.push(cell);
         }
-        if let Some(e) = errors {
-            self.errors = e;
+        if let Some(ref mut total) = self.total {
+            total.push("".normal());
         }
     }
 
-    /// Print a simple benchmark result
-    pub fn print0(&self) {
-        self.print(Some(self.iterations), Some(self.errors));
-    }
-
-    /// Print a simple benchmark result, specifying number of iterations made
-    pub fn print(&self, iterations: Option<u32>, errors: Option<u32>) {
-        println!("{}", self.to_string_for(iterations, errors));
-    }
-
-    #[allow(clippy::cast_sign_loss)]
-    #[allow(clippy::cast_possible_truncation)]
-    /// Get a benchmark result
-    pub fn result0(&self) -> BenchmarkResult {
-        self.result(Some(self.iterations), Some(self.errors))
-    }
-
-    #[allow(clippy::cast_sign_loss)]
-    #[allow(clippy::cast_possible_truncation)]
-    /// Get a benchmark result, specifying number of iterations made
-    pub fn result(&self, iterations: Option<u32>, errors: Option<u32>) -> BenchmarkResult {
-        let elapsed = self.elapsed.unwrap_or_else(|| self.started.elapsed());
-        let it = iterations.unwrap_or(self.iterations);
-        let errs = errors.unwrap_or(self.errors);
-        BenchmarkResult {
-            elapsed,
-            iterations: it,
-            errors: errs,
-            speed: (f64::from(it - errs) / elapsed.as_secs_f64()) as u32,
+    fn table(&self, color: bool, with_total: bool) -> Table {
+        let mut table = ctable(Some(self.header.clone()), !color);
+        for row in &self.rows {
+            table.add_row(prettytable::Row::new(
+                row.iter().map(|v| cell!(v)).collect(),
+            ));
         }
+        if with_total {
+            if let Some(ref total) = self.total {
+                table.add_row(row!["-----".black()]);
+                table.add_row(prettytable::Row::new(
+                    total.iter().map(|v| cell!(v)).collect(),
+                ));
+            }
+        }
+        if !color {
+            strip_table_colors(&mut table);
+        }
+        table
     }
+}
 
-    fn to_string_for(&self, iterations: Option<u32>, errors: Option<u32>) -> String {
-        let result = self.result(iterations, errors);
-        let elapsed = result.elapsed.as_secs_f64();
-        format!(
-            "{}\nIterations: {}, success: {}, errors: {}{}\n\
-            Elapsed:\n {} secs ({} msecs)\n {} iters/s\n {} ns per iter",
-            result_separator!(),
-            format_number!(result.iterations).magenta(),
-            format_number!(result.iterations - result.errors).green(),
-            if result.errors > 0 {
-                format_number!(result.errors).red()
-            } else {
-                "None".normal()
-            },
-            if result.errors > 0 {
-                format!(
-                    ", error rate: {}",
-                    format!(
-                        "{:.2} %",
-                        (f64::from(result.errors) / f64::from(result.iterations) * 100.0)
-                    )
-                    .red()
-                )
-            } else {
-                String::new()
-            },
-            format!("{:.3}", elapsed).blue(),
-            format!("{:.3}", elapsed * 1000.0).cyan(),
-            format_number!(result.speed).yellow(),
-            format_number!(1_000_000_000 / result.speed).magenta()
-        )
-    }
-
-    /// Increment iterations inside benchmark
-    ///
-    /// Not required to use if the number of iterations is specified at benchmark creation or
-    /// finish / print
-    pub fn increment(&mut self) {
-        self.iterations += 1;
-    }
-
-    /// Increment errors inside benchmark
-    ///
-    /// Not required to use if the number of errors is specified at benchmark finish / print
-    pub fn increment_errors(&mut self) {
-        self.errors += 1;
+fn strip_table_colors(table: &mut Table) {
+    for row in table.row_iter_mut() {
+        for cell in row.iter_mut() {
+            *cell = prettytable::Cell::new(&strip_colors(&cell.get_content()));
+        }
     }
 }
 
 fn ctable(titles: Option<Vec<&str>>, raw: bool) -> prettytable::Table {
+    init_color();
     let mut table = prettytable::Table::new();
-    let format = prettytable::format::FormatBuilder::new()
-        .column_separator(' ')
-        .borders(' ')
-        .separators(
-            &[prettytable::format::LinePosition::Title],
-            prettytable::format::LineSeparator::new('-', '-', '-', '-'),
-        )
-        .padding(0, 1)
-        .build();
-    table.set_format(format);
+    table.set_format(
+        TABLE_STYLE
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .format(),
+    );
     if let Some(tt) = titles {
         let mut titlevec: Vec<prettytable::Cell> = Vec::new();
         for t in tt {
             if raw {
                 titlevec.push(prettytable::Cell::new(t));
             } else {
-                titlevec.push(prettytable::Cell::new(t).style_spec("Fb"));
+                titlevec.push(prettytable::Cell::new(&t.blue().to_string()));
             }
         }
         table.set_titles(prettytable::Row::new(titlevec));
@@ -600,26 +3761,67 @@ fn ctable(titles: Option<Vec<&str>>, raw: bool) -> prettytable::Table {
     table
 }
 
-#[allow(clippy::cast_possible_truncation)]
-fn separator(title: &str) -> colored::ColoredString {
+/// Override the output width (the terminal width is used by default)
+pub fn set_output_width(width: u16) {
+    OUTPUT_WIDTH
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+        .replace(width);
+}
+
+/// Clear the output width override, restoring the terminal width detection
+pub fn reset_output_width() {
+    OUTPUT_WIDTH
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+        .take();
+}
+
+fn output_width() -> u16 {
+    if let Some(w) = *OUTPUT_WIDTH
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+    {
+        return w;
+    }
     let size = terminal_size();
-    let width = if let Some((Width(w), Height(_))) = size {
-        w
-    } else {
-        40
-    };
-    (title.to_owned()
-        + &(0..width - title.len() as u16)
-            .map(|_| "-")
-            .collect::<String>())
-        .black()
+    match size {
+        Some((Width(w), Height(_))) if w > 0 => w,
+        _ => 40,
+    }
+}
+
+fn separator(title: &str) -> colored::ColoredString {
+    init_color();
+    let width = output_width();
+    let title_len = u16::try_from(title.chars().count()).unwrap_or(u16::MAX);
+    (title.to_owned() + &"-".repeat(usize::from(width.saturating_sub(title_len)))).black()
 }
 
+/// Per-iteration checkpoint measurements (iteration number, duration)
+type PerfSamples = Vec<(usize, Duration)>;
+
+/// Perf checkpoint key (parent, name), the parent is set for scoped checkpoints only
+type PerfKey<'a> = (Option<&'a str>, &'a str);
+
+/// Perf checkpoint data for output (parent, name, samples)
+type PerfCheckpoint<'a> = (Option<&'a str>, &'a str, &'a [(usize, Duration)]);
+
+/// Performance measurements
+///
+/// Checkpoints may be skipped in some iterations: stats of a checkpoint are calculated for the
+/// iterations it has been hit in, TOTAL is calculated for the iterations where all the displayed
+/// checkpoints have been hit
+///
+/// Scoped checkpoints are displayed indented under their parents. A parent row contains sums of
+/// its children and is not counted in TOTAL
 pub struct Perf {
     start: Instant,
     iterations: usize,
-    checkpoints: Vec<&'static str>,
-    measurements: BTreeMap<&'static str, Vec<Duration>>,
+    checkpoints: Vec<PerfKey<'static>>,
+    measurements: BTreeMap<PerfKey<'static>, PerfSamples>,
+    unit: TimeUnit,
+    color: bool,
 }
 
 impl Default for Perf {
@@ -635,8 +3837,18 @@ impl Perf {
             iterations: 0,
             checkpoints: Vec::new(),
             measurements: BTreeMap::new(),
+            unit: TimeUnit::default(),
+            color: true,
         }
     }
+    /// Set the time unit for the output, the default is microseconds
+    pub fn set_unit(&mut self, unit: TimeUnit) {
+        self.unit = unit;
+    }
+    /// Enable or disable colored output (enabled by default)
+    pub fn set_color(&mut self, color: bool) {
+        self.color = color;
+    }
     pub fn reset(&mut self) {
         self.iterations = 0;
         self.checkpoints.clear();
@@ -647,70 +3859,622 @@ impl Perf {
         self.start = Instant::now();
     }
     pub fn checkpoint(&mut self, name: &'static str) {
-        if self.iterations == 1 {
-            self.checkpoints.push(name);
+        self.record((None, name));
+    }
+    /// Record a checkpoint, nested under the parent
+    ///
+    /// The parent is not a checkpoint itself, its stats are calculated from the children
+    pub fn checkpoint_scoped(&mut self, parent: &'static str, name: &'static str) {
+        self.record((Some(parent), name));
+    }
+    fn record(&mut self, key: PerfKey<'static>) {
+        let sample = (self.iterations, self.start.elapsed());
+        match self.measurements.entry(key) {
+            std::collections::btree_map::Entry::Vacant(entry) => {
+                self.checkpoints.push(key);
+                entry.insert(vec![sample]);
+            }
+            std::collections::btree_map::Entry::Occupied(mut entry) => {
+                entry.get_mut().push(sample);
+            }
         }
-        self.measurements
-            .entry(name)
-            .or_default()
-            .push(self.start.elapsed());
         self.start = Instant::now();
     }
-    /// # Panics
+    /// Get per-checkpoint statistics (scoped checkpoints are not included)
+    pub fn measurements(&self) -> BTreeMap<&'static str, PerfStats> {
+        self.measurements
+            .iter()
+            .filter(|((parent, _), _)| parent.is_none())
+            .map(|((_, name), samples)| (*name, PerfStats::from_samples(samples)))
+            .collect()
+    }
+    /// Get statistics of scoped checkpoints, mapped by (parent, name)
+    pub fn scoped_measurements(&self) -> BTreeMap<(&'static str, &'static str), PerfStats> {
+        self.measurements
+            .iter()
+            .filter_map(|((parent, name), samples)| {
+                parent.map(|parent| ((parent, *name), PerfStats::from_samples(samples)))
+            })
+            .collect()
+    }
+    /// Get statistics for per-iteration totals (sums of all checkpoints)
     ///
-    /// Will panic if the number of iterations is less than 1 or greater than u32::MAX
+    /// Only the iterations where all checkpoints have been hit are counted
+    pub fn total(&self) -> PerfStats {
+        PerfStats::new(perf_totals(&self.checkpoint_data()))
+    }
+    /// Take a snapshot of the current measurements, which can be printed later, e.g. after
+    /// reset
+    pub fn snapshot(&self) -> PerfSnapshot {
+        PerfSnapshot {
+            iterations: self.iterations,
+            checkpoints: self.checkpoints.iter().map(owned_perf_key).collect(),
+            measurements: self
+                .measurements
+                .iter()
+                .map(|(key, durations)| (owned_perf_key(key), durations.clone()))
+                .collect(),
+            unit: self.unit,
+            color: self.color,
+        }
+    }
+    /// Print a previously taken snapshot
+    pub fn print_snapshot(snapshot: &PerfSnapshot) {
+        snapshot
+            .print_to(&mut io::stdout())
+            .expect("failed printing to stdout");
+    }
     pub fn print(&self) {
-        println!("Iterations: {}", self.iterations.to_string().magenta());
-        println!();
-        let header = vec!["checkpoint", "min", "max", "avg"];
-        let mut table = ctable(Some(header), false);
-        for name in &self.checkpoints {
-            let durations = self.measurements.get(name).unwrap();
-            let min = durations.iter().min().unwrap().as_micros();
-            let max = durations.iter().max().unwrap().as_micros();
-            let avg = (durations.iter().sum::<Duration>()
-                / u32::try_from(durations.len()).unwrap())
-            .as_micros();
-            table.add_row(prettytable::Row::new(vec![
-                cell!(name),
-                cell!(format_number!(min).blue().bold()),
-                cell!(format_number!(max).yellow()),
-                cell!(format_number!(avg).green().bold()),
-            ]));
+        self.print_to(&mut io::stdout())
+            .expect("failed printing to stdout");
+    }
+    /// Print the measurements to the specified writer
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the writer fails
+    pub fn print_to(&self, w: &mut dyn Write) -> io::Result<()> {
+        print_perf(
+            w,
+            self.iterations,
+            &self.checkpoint_data(),
+            self.unit,
+            self.color,
+        )
+    }
+    /// Print the measurements, sorted by average duration (the slowest checkpoints first)
+    pub fn print_sorted(&self) {
+        self.print_sorted_to(&mut io::stdout())
+            .expect("failed printing to stdout");
+    }
+    /// Print the measurements to the specified writer, sorted by average duration (the slowest
+    /// checkpoints first)
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the writer fails
+    pub fn print_sorted_to(&self, w: &mut dyn Write) -> io::Result<()> {
+        let mut data = self.checkpoint_data();
+        data.sort_by_cached_key(|(_, _, samples)| {
+            std::cmp::Reverse(PerfStats::from_samples(samples).avg)
+        });
+        print_perf(w, self.iterations, &data, self.unit, self.color)
+    }
+    /// Print the measurements of the specified checkpoints only, the total is calculated for
+    /// them as well
+    ///
+    /// If the list is empty, all checkpoints are printed. Unknown checkpoint names are ignored.
+    /// Scoped checkpoints are matched by either their own or their parent names
+    pub fn print_filtered(&self, include: &[&str]) {
+        self.print_filtered_to(&mut io::stdout(), include)
+            .expect("failed printing to stdout");
+    }
+    /// Print the measurements of the specified checkpoints only to the specified writer
+    ///
+    /// If the list is empty, all checkpoints are printed. Unknown checkpoint names are ignored
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the writer fails
+    pub fn print_filtered_to(&self, w: &mut dyn Write, include: &[&str]) -> io::Result<()> {
+        let mut data = self.checkpoint_data();
+        if !include.is_empty() {
+            data.retain(|(parent, name, _)| {
+                include.contains(name) || parent.is_some_and(|p| include.contains(&p))
+            });
         }
-        let mut totals: Vec<Duration> = Vec::with_capacity(self.iterations);
-        for i in 0..self.iterations {
-            let mut t = Duration::default();
-            for name in &self.checkpoints {
-                t += self.measurements.get(name).unwrap()[i];
+        print_perf(w, self.iterations, &data, self.unit, self.color)
+    }
+    /// Print the measurements split into two tables: for the first iterations (up to the
+    /// boundary, inclusive) and for the rest, e.g. to see warmup effects
+    pub fn print_split(&self, boundary: usize) {
+        self.print_split_to(&mut io::stdout(), boundary)
+            .expect("failed printing to stdout");
+    }
+    /// Print the measurements to the specified writer, split into two tables: for the first
+    /// iterations (up to the boundary, inclusive) and for the rest
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the writer fails
+    pub fn print_split_to(&self, w: &mut dyn Write, boundary: usize) -> io::Result<()> {
+        let mut w = ColorWriter::new(w, self.color);
+        self.print_iterations_to(&mut w, 1, boundary.min(self.iterations))?;
+        writeln!(w)?;
+        if self.iterations > boundary {
+            self.print_iterations_to(&mut w, boundary + 1, self.iterations)
+        } else {
+            writeln!(w, "{}", format!("no iterations after {}", boundary).black())
+        }
+    }
+    fn print_iterations_to(&self, w: &mut dyn Write, from: usize, to: usize) -> io::Result<()> {
+        let range = from..=to;
+        let parts: Vec<(Option<&str>, &str, PerfSamples)> = self
+            .checkpoint_data()
+            .into_iter()
+            .map(|(parent, name, samples)| {
+                let samples: PerfSamples = samples
+                    .iter()
+                    .filter(|(iteration, _)| range.contains(iteration))
+                    .copied()
+                    .collect();
+                (parent, name, samples)
+            })
+            .filter(|(_, _, samples)| !samples.is_empty())
+            .collect();
+        let data: Vec<PerfCheckpoint<'_>> = parts
+            .iter()
+            .map(|(parent, name, samples)| (*parent, *name, samples.as_slice()))
+            .collect();
+        writeln!(
+            w,
+            "{}",
+            separator(&format!("--- Iterations {} - {} ", from, to))
+        )?;
+        print_perf(
+            w,
+            (to + 1).saturating_sub(from),
+            &data,
+            self.unit,
+            self.color,
+        )
+    }
+    fn checkpoint_data(&self) -> Vec<PerfCheckpoint<'_>> {
+        self.checkpoints
+            .iter()
+            .map(|key| (key.0, key.1, self.measurements.get(key).unwrap().as_slice()))
+            .collect()
+    }
+}
+
+/// An owned copy of Perf measurements
+#[derive(Clone)]
+pub struct PerfSnapshot {
+    iterations: usize,
+    checkpoints: Vec<(Option<String>, String)>,
+    measurements: BTreeMap<(Option<String>, String), PerfSamples>,
+    unit: TimeUnit,
+    color: bool,
+}
+
+impl PerfSnapshot {
+    /// Print the snapshot to the specified writer
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the writer fails
+    pub fn print_to(&self, w: &mut dyn Write) -> io::Result<()> {
+        let data: Vec<PerfCheckpoint<'_>> = self
+            .checkpoints
+            .iter()
+            .map(|key| {
+                (
+                    key.0.as_deref(),
+                    key.1.as_str(),
+                    self.measurements.get(key).unwrap().as_slice(),
+                )
+            })
+            .collect();
+        print_perf(w, self.iterations, &data, self.unit, self.color)
+    }
+}
+
+fn owned_perf_key(key: &PerfKey<'_>) -> (Option<String>, String) {
+    (key.0.map(ToOwned::to_owned), key.1.to_owned())
+}
+
+/// Get per-iteration sums of checkpoint durations, only for iterations where all the checkpoints
+/// have been hit
+fn perf_totals(checkpoints: &[PerfCheckpoint<'_>]) -> Vec<Duration> {
+    let Some(last_iteration) = checkpoints
+        .iter()
+        .filter_map(|(_, _, samples)| samples.last().map(|(iteration, _)| *iteration))
+        .max()
+    else {
+        return Vec::new();
+    };
+    let mut totals: Vec<Duration> = vec![Duration::default(); last_iteration + 1];
+    let mut hits: Vec<usize> = vec![0; last_iteration + 1];
+    for (_, _, samples) in checkpoints {
+        let mut last_hit = None;
+        for (iteration, duration) in *samples {
+            totals[*iteration] += *duration;
+            // a checkpoint may be hit multiple times per iteration
+            if last_hit != Some(*iteration) {
+                hits[*iteration] += 1;
+                last_hit = Some(*iteration);
             }
-            totals.push(t);
         }
-        let min = totals.iter().min().unwrap().as_micros();
-        let max = totals.iter().max().unwrap().as_micros();
-        let avg =
-            (totals.iter().sum::<Duration>() / u32::try_from(totals.len()).unwrap()).as_micros();
-        table.add_row(row!["-----".black()]);
-        table.add_row(prettytable::Row::new(vec![
-            cell!("TOTAL".yellow().bold()),
-            cell!(format_number!(min).blue().bold()),
-            cell!(format_number!(max).yellow()),
-            cell!(format_number!(avg).green().bold()),
-        ]));
-        table.printstd();
-        println!();
-        println!("{}", "(the durations are provided in microseconds)".black());
+    }
+    totals
+        .into_iter()
+        .zip(hits)
+        .filter(|(_, hits)| *hits == checkpoints.len())
+        .map(|(total, _)| total)
+        .collect()
+}
+
+fn print_perf(
+    w: &mut dyn Write,
+    iterations: usize,
+    checkpoints: &[PerfCheckpoint<'_>],
+    unit: TimeUnit,
+    color: bool,
+) -> io::Result<()> {
+    init_color();
+    let w = &mut ColorWriter::new(w, color);
+    if iterations == 0 {
+        return writeln!(w, "{}", NOTHING_MEASURED.yellow());
+    }
+    writeln!(w, "Iterations: {}", iterations.to_string().magenta())?;
+    writeln!(w)?;
+    let header = vec![
+        "checkpoint",
+        "min",
+        "max",
+        "avg",
+        "median",
+        "stddev",
+        "% of total",
+    ];
+    let total = PerfStats::new(perf_totals(checkpoints));
+    let mut table = ctable(Some(header), !color);
+    let mut parents_displayed: Vec<&str> = Vec::new();
+    for (parent, name, samples) in checkpoints {
+        let Some(parent) = parent else {
+            table.add_row(perf_row(
+                cell!(name),
+                &PerfStats::from_samples(samples),
+                total.avg,
+                unit,
+            ));
+            continue;
+        };
+        if parents_displayed.contains(parent) {
+            continue;
+        }
+        parents_displayed.push(parent);
+        // the children are displayed together, at the position of the first one
+        let children: Vec<PerfCheckpoint<'_>> = checkpoints
+            .iter()
+            .filter(|c| c.0 == Some(*parent))
+            .copied()
+            .collect();
+        table.add_row(perf_row(
+            cell!(parent),
+            &PerfStats::new(perf_totals(&children)),
+            total.avg,
+            unit,
+        ));
+        for (_, child, samples) in children {
+            table.add_row(perf_row(
+                cell!(format!("  {}", child)),
+                &PerfStats::from_samples(samples),
+                total.avg,
+                unit,
+            ));
+        }
+    }
+    table.add_row(row!["-----".black()]);
+    table.add_row(perf_row(
+        cell!("TOTAL".yellow().bold()),
+        &total,
+        total.avg,
+        unit,
+    ));
+    table.print(w)?;
+    writeln!(w)?;
+    writeln!(
+        w,
+        "{}",
+        format!("(the durations are provided in {})", unit.name()).black()
+    )
+}
+
+/// Perf checkpoint statistics
+pub struct PerfStats {
+    pub min: Duration,
+    pub max: Duration,
+    pub avg: Duration,
+    pub count: usize,
+    pub durations: Vec<Duration>,
+}
+
+impl PerfStats {
+    fn new(durations: Vec<Duration>) -> Self {
+        let count = durations.len();
+        Self {
+            min: durations.iter().min().copied().unwrap_or_default(),
+            max: durations.iter().max().copied().unwrap_or_default(),
+            avg: avg_of(&durations),
+            count,
+            durations,
+        }
+    }
+    fn from_samples(samples: &[(usize, Duration)]) -> Self {
+        Self::new(samples.iter().map(|(_, d)| *d).collect())
+    }
+    /// Get median duration, interpolated for even-length sample sets
+    pub fn median(&self) -> Duration {
+        let mut sorted = self.durations.clone();
+        sorted.sort_unstable();
+        percentile_of(&sorted, 50.0)
+    }
+    /// Get population standard deviation
+    pub fn std_dev(&self) -> Duration {
+        std_dev_of(&self.durations)
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn perf_row(
+    name: prettytable::Cell,
+    stats: &PerfStats,
+    total_avg: Duration,
+    unit: TimeUnit,
+) -> prettytable::Row {
+    let pct = if total_avg.is_zero() {
+        0.0
+    } else {
+        stats.avg.as_nanos() as f64 / total_avg.as_nanos() as f64 * 100.0
+    };
+    prettytable::Row::new(vec![
+        name,
+        cell!(format_number!(unit.value_of(stats.min)).blue().bold()),
+        cell!(format_number!(unit.value_of(stats.max)).yellow()),
+        cell!(format_number!(unit.value_of(stats.avg)).green().bold()),
+        cell!(format_number!(unit.value_of(stats.median())).cyan()),
+        cell!(format_number!(unit.value_of(stats.std_dev())).magenta()),
+        cell!(format!("{:.2} %", pct).yellow()),
+    ])
+}
+
+#[allow(clippy::cast_possible_truncation)]
+/// Get the average of samples, zero if empty
+fn avg_of(durations: &[Duration]) -> Duration {
+    if durations.is_empty() {
+        return Duration::default();
+    }
+    let avg = durations.iter().map(Duration::as_nanos).sum::<u128>() / durations.len() as u128;
+    Duration::new((avg / 1_000_000_000) as u64, (avg % 1_000_000_000) as u32)
+}
+
+#[allow(clippy::cast_precision_loss)]
+/// Get the population standard deviation of samples
+fn std_dev_of(durations: &[Duration]) -> Duration {
+    if durations.is_empty() {
+        return Duration::default();
+    }
+    let n = durations.len() as f64;
+    let mean = durations.iter().map(Duration::as_secs_f64).sum::<f64>() / n;
+    let variance = durations
+        .iter()
+        .map(|d| (d.as_secs_f64() - mean).powi(2))
+        .sum::<f64>()
+        / n;
+    Duration::from_secs_f64(variance.sqrt())
+}
+
+#[allow(clippy::cast_sign_loss)]
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_precision_loss)]
+/// print a benchmark progress line to stderr, overwriting the previous one
+///
+/// The line is terminated when all the iterations are done. Nothing is printed if stderr is not a
+/// terminal
+pub fn print_progress(iterations_done: u64, iterations: u64, elapsed: Duration) {
+    if !*PROGRESS_ENABLED {
+        return;
+    }
+    let secs = elapsed.as_secs_f64();
+    let speed = if secs > 0.0 {
+        rate_per_sec(iterations_done as f64, secs)
+    } else {
+        0
+    };
+    let mut stderr = io::stderr().lock();
+    let _ = write!(
+        stderr,
+        "\r{} / {} iters, {:.3} secs, {} iters/s ",
+        format_number!(iterations_done),
+        format_number!(iterations),
+        secs,
+        format_number!(speed)
+    );
+    if iterations_done >= iterations {
+        let _ = writeln!(stderr);
+    }
+    let _ = stderr.flush();
+}
+
+#[cfg(all(feature = "x86", target_arch = "x86_64"))]
+/// read the CPU timestamp counter
+#[inline]
+pub fn rdtsc() -> u64 {
+    // SAFETY: the timestamp counter is available on all x86_64 CPUs
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+#[cfg(all(feature = "x86", target_arch = "x86_64"))]
+#[allow(clippy::cast_precision_loss)]
+/// print estimated CPU cycles per iteration
+pub fn print_cycles(cycles: u64, iterations: u64) {
+    init_color();
+    if iterations == 0 {
+        println!(" {} cycles per iter", "n/a".normal());
+    } else {
+        println!(
+            " {} cycles per iter (estimate)",
+            format!("{:.1}", cycles as f64 / iterations as f64).magenta()
+        );
+    }
+}
+
+#[cfg(feature = "alloc-count")]
+thread_local! {
+    static ALLOC_COUNT: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+    static ALLOC_BYTES: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+}
+
+#[cfg(feature = "alloc-count")]
+/// Global allocator wrapper which counts allocations made by each thread
+///
+/// Must be registered by the user:
+///
+/// ```rust,ignore
+/// #[global_allocator]
+/// static ALLOC: bma_benchmark::CountingAllocator = bma_benchmark::CountingAllocator;
+/// ```
+///
+/// Reallocations are counted as allocations of the new size, deallocations are not counted
+pub struct CountingAllocator;
+
+#[cfg(feature = "alloc-count")]
+impl CountingAllocator {
+    /// Reset allocation counters of the current thread
+    pub fn reset() {
+        let _ = ALLOC_COUNT.try_with(|c| c.set(0));
+        let _ = ALLOC_BYTES.try_with(|c| c.set(0));
+    }
+
+    /// Get the number of allocations and allocated bytes of the current thread
+    pub fn counters() -> (u64, u64) {
+        (
+            ALLOC_COUNT
+                .try_with(std::cell::Cell::get)
+                .unwrap_or_default(),
+            ALLOC_BYTES
+                .try_with(std::cell::Cell::get)
+                .unwrap_or_default(),
+        )
+    }
+
+    #[inline]
+    fn count(size: usize) {
+        let _ = ALLOC_COUNT.try_with(|c| c.set(c.get() + 1));
+        let _ = ALLOC_BYTES.try_with(|c| c.set(c.get() + size as u64));
+    }
+}
+
+#[cfg(feature = "alloc-count")]
+unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        Self::count(layout.size());
+        std::alloc::System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: std::alloc::Layout) -> *mut u8 {
+        Self::count(layout.size());
+        std::alloc::System.alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        std::alloc::System.dealloc(ptr, layout);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: std::alloc::Layout, new_size: usize) -> *mut u8 {
+        Self::count(new_size);
+        std::alloc::System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[cfg(feature = "alloc-count")]
+#[allow(clippy::cast_precision_loss)]
+/// print memory allocations per iteration
+pub fn print_allocs(allocs: u64, bytes: u64, iterations: u64) {
+    init_color();
+    if iterations == 0 {
+        println!(" {} allocs per iter", "n/a".normal());
+    } else {
+        println!(
+            " {} allocs per iter, {} bytes per iter",
+            format!("{:.2}", allocs as f64 / iterations as f64).magenta(),
+            format!("{:.1}", bytes as f64 / iterations as f64).magenta()
+        );
+    }
+}
+
+/// Get the peak resident set size of the current process in bytes (VmHWM)
+///
+/// Returns None on non-Linux platforms or if /proc/self/status can not be read or parsed
+pub fn peak_rss() -> Option<u64> {
+    #[cfg(target_os = "linux")]
+    {
+        std::fs::read_to_string("/proc/self/status")
+            .ok()
+            .and_then(|status| parse_vm_hwm(&status))
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        None
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn parse_vm_hwm(status: &str) -> Option<u64> {
+    let value = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?
+        .trim();
+    let kb = value.strip_suffix("kB").unwrap_or(value).trim();
+    kb.parse::<u64>().ok().map(|v| v * 1024)
+}
+
+/// Print peak RSS increase, used by [`benchmark_rss`] macro
+pub fn print_rss(before: Option<u64>, after: Option<u64>) {
+    init_color();
+    println!("{}", rss_summary(before, after));
+}
+
+fn rss_summary(before: Option<u64>, after: Option<u64>) -> String {
+    if let (Some(before), Some(after)) = (before, after) {
+        format!(
+            " {} bytes peak RSS increase",
+            format_number!(after.saturating_sub(before)).magenta()
+        )
+    } else if cfg!(target_os = "linux") {
+        format!(" {}", "peak RSS could not be read".black())
+    } else {
+        format!(" {}", "peak RSS is not available on this platform".black())
     }
 }
 
 const WARMUP_DURATION: Duration = Duration::from_secs(5);
 
 /// recommended to call this function before running speed race benchmarks
+///
+/// Warms up CPU for 5 seconds
 pub fn warmup() {
+    warmup_for(WARMUP_DURATION);
+}
+
+/// warm up CPU for the specified duration
+pub fn warmup_for(duration: Duration) {
+    init_color();
     println!("{}", "warming up".black());
     std::hint::black_box(move || {
         let start = Instant::now();
-        while start.elapsed() < WARMUP_DURATION {
+        while start.elapsed() < duration {
             std::thread::yield_now();
         }
     })();
@@ -718,9 +4482,17 @@ pub fn warmup() {
 }
 
 /// a shortcut to bma_benchmark::warmup() in case all the macros are imported
+///
+/// If the number of seconds is specified, bma_benchmark::warmup_for() is called
 #[macro_export]
 macro_rules! warmup {
     () => {
         $crate::warmup();
     };
+    ($secs: expr) => {
+        $crate::warmup_for(::std::time::Duration::from_secs($secs));
+    };
 }
+
+#[cfg(test)]
+mod tests;
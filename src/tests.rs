@@ -0,0 +1,1767 @@
+use super::*;
+use std::hint::black_box;
+use std::sync::{Mutex, MutexGuard};
+
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Serialize tests, which use the global state (default benchmarks, output settings)
+fn lock() -> MutexGuard<'static, ()> {
+    LOCK.lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+fn output(f: impl FnOnce(&mut dyn Write) -> io::Result<()>) -> String {
+    let mut buf = Vec::new();
+    f(&mut buf).unwrap();
+    String::from_utf8(buf).unwrap()
+}
+
+fn assert_near(actual: Duration, expected: Duration) {
+    assert!(
+        actual.max(expected) - actual.min(expected) <= Duration::from_nanos(1),
+        "{:?} != {:?}",
+        actual,
+        expected
+    );
+}
+
+#[test]
+fn latency_percentile_interpolation() {
+    let _lock = lock();
+    let mut lb = LatencyBenchmark::new();
+    assert_eq!(lb.p50(), Duration::default());
+    for us in [400, 100, 500, 300, 200] {
+        lb.push(Duration::from_micros(us));
+    }
+    assert_eq!(lb.percentile(0.0), Duration::from_micros(100));
+    assert_eq!(lb.percentile(100.0), Duration::from_micros(500));
+    assert_eq!(lb.percentile(12.5), Duration::from_micros(150));
+    assert_eq!(lb.p50(), Duration::from_micros(300));
+    assert_near(lb.p90(), Duration::from_micros(460));
+    assert_near(lb.p99(), Duration::from_micros(496));
+    assert_near(lb.p999(), Duration::from_nanos(499_600));
+    lb.set_color(false);
+    let out = output(|w| lb.print_to(w));
+    assert!(out.starts_with("latency (μs) avg: 300, min: 100, max: 500, p50: 300, p99: "));
+}
+#[test]
+fn benchmark_u64_iterations() {
+    let _lock = lock();
+    let mut benchmark = Benchmark::new0();
+    benchmark.set_color(false);
+    benchmark.finish(Some(5_000_000_000), None);
+    benchmark.set_elapsed(Duration::from_secs(2));
+    let result = benchmark.result0();
+    assert_eq!(result.iterations, 5_000_000_000);
+    assert_eq!(result.speed, 2_500_000_000);
+    let out = benchmark.to_string();
+    assert!(out.contains("Iterations: 5_000_000_000, success: 5_000_000_000, errors: None"));
+    assert!(out.contains(" 2_500_000_000 iters/s"));
+}
+#[cfg(feature = "serde")]
+#[test]
+fn result_json_round_trip() {
+    let mut benchmark = Benchmark::new0();
+    benchmark.finish(Some(1_000), Some(10));
+    benchmark.set_elapsed(Duration::from_millis(500));
+    let json = benchmark.result_json();
+    assert!(json.contains("\"elapsed_secs\":0.5"));
+    let result = benchmark.result0();
+    let loaded: BenchmarkResult = serde_json::from_str(&json).unwrap();
+    assert_eq!(loaded.elapsed, result.elapsed);
+    assert_eq!(loaded.iterations, 1_000);
+    assert_eq!(loaded.errors, 10);
+    assert_eq!(loaded.speed, result.speed);
+    let mut staged = StagedBenchmark::new();
+    staged.start("stage1");
+    staged.finish("stage1", 100, 0);
+    let results: serde_json::Value = serde_json::from_str(&staged.results_json()).unwrap();
+    assert_eq!(results[0]["stage"], "stage1");
+    assert_eq!(results[0]["iterations"], 100);
+}
+/// Create a staged benchmark with stages (name, iterations, errors, elapsed msecs)
+fn staged_with(stages: &[(&str, u64, u64, u64)]) -> StagedBenchmark {
+    let mut staged = StagedBenchmark::new();
+    staged.set_color(false);
+    for (name, iterations, errors, elapsed) in stages {
+        staged.start(name);
+        staged.finish(name, *iterations, *errors);
+        staged
+            .benchmarks
+            .get_mut(*name)
+            .unwrap()
+            .set_elapsed(Duration::from_millis(*elapsed));
+    }
+    staged
+}
+
+#[test]
+fn staged_csv() {
+    let staged = staged_with(&[("b", 2_000, 0, 250), ("a", 1_000, 0, 500)]);
+    assert_eq!(
+        staged.result_csv(),
+        "stage,iterations,errors,elapsed_secs,speed\n\
+        a,1000,0,0.5,2000\n\
+        b,2000,0,0.25,8000\n"
+    );
+    let staged = staged_with(&[("a", 1_000, 0, 500), ("b", 2_000, 100, 250)]);
+    assert_eq!(
+        staged.result_csv(),
+        "stage,iterations,errors,error_rate,elapsed_secs,speed\n\
+        a,1000,0,0.0000,0.5,2000\n\
+        b,2000,100,0.0500,0.25,7600\n"
+    );
+}
+#[test]
+fn benchmark_for_duration() {
+    let _lock = lock();
+    benchmark_for!(Duration::from_millis(200), {
+        std::thread::sleep(Duration::from_millis(30));
+    });
+    let result = benchmark_result!();
+    assert!(result.elapsed >= Duration::from_millis(200));
+    assert!(result.iterations >= 1);
+    benchmark_for!(Duration::from_millis(10), {
+        std::thread::sleep(Duration::from_millis(50));
+    });
+    let result = benchmark_result!();
+    assert_eq!(result.iterations, 1);
+    assert!(result.elapsed >= Duration::from_millis(50));
+}
+/// Create a perf with checkpoints (name, durations in μs per iteration)
+fn perf_with(checkpoints: &[(&'static str, &[u64])]) -> Perf {
+    let mut perf = Perf::new();
+    perf.set_color(false);
+    for (name, durations) in checkpoints {
+        perf.iterations = perf.iterations.max(durations.len());
+        perf.checkpoints.push((None, name));
+        perf.measurements.insert(
+            (None, name),
+            durations
+                .iter()
+                .enumerate()
+                .map(|(i, us)| (i + 1, Duration::from_micros(*us)))
+                .collect(),
+        );
+    }
+    perf
+}
+
+/// Get table rows, which start with the specified names, split into cells
+fn table_rows(s: &str, names: &[&str]) -> Vec<Vec<String>> {
+    s.lines()
+        .map(|line| line.split_whitespace().map(ToOwned::to_owned).collect())
+        .filter(|cells: &Vec<String>| cells.first().is_some_and(|c| names.contains(&c.as_str())))
+        .collect()
+}
+
+#[test]
+fn perf_median_std_dev() {
+    let _lock = lock();
+    let perf = perf_with(&[
+        ("a", &[20, 40, 40, 40, 50, 50, 70, 90]),
+        ("b", &[10, 10, 10, 10, 10, 10, 10, 10]),
+    ]);
+    let a = &perf.measurements()["a"];
+    assert_eq!(a.median(), Duration::from_micros(45));
+    assert_near(a.std_dev(), Duration::from_micros(20));
+    let total = perf.total();
+    assert_eq!(total.median(), Duration::from_micros(55));
+    assert_near(total.std_dev(), Duration::from_micros(20));
+    let out = output(|w| perf.print_to(w));
+    assert_eq!(
+        table_rows(&out, &["a", "b", "TOTAL"]),
+        [
+            ["a", "20", "90", "50", "45", "20", "83.33", "%"],
+            ["b", "10", "10", "10", "10", "0", "16.67", "%"],
+            ["TOTAL", "30", "100", "60", "55", "20", "100.00", "%"],
+        ]
+    );
+}
+#[test]
+fn perf_measurements() {
+    let perf = perf_with(&[("a", &[10, 20, 30]), ("b", &[5, 5, 20])]);
+    let measurements = perf.measurements();
+    assert_eq!(measurements.len(), 2);
+    let a = &measurements["a"];
+    assert_eq!(a.min, Duration::from_micros(10));
+    assert_eq!(a.max, Duration::from_micros(30));
+    assert_eq!(a.avg, Duration::from_micros(20));
+    assert_eq!(a.count, 3);
+    let b = &measurements["b"];
+    assert_eq!(b.avg, Duration::from_micros(10));
+    assert_eq!(b.durations, [5, 5, 20].map(Duration::from_micros).to_vec());
+    let total = perf.total();
+    assert_eq!(total.count, 3);
+    assert_eq!(total.min, Duration::from_micros(15));
+    assert_eq!(total.max, Duration::from_micros(50));
+    assert_eq!(total.avg, Duration::from_micros(30));
+    assert_eq!(avg_of(&[]), Duration::ZERO);
+    assert_eq!(
+        avg_of(&[Duration::from_nanos(1), Duration::from_nanos(2)]),
+        Duration::from_nanos(1)
+    );
+    assert_eq!(
+        avg_of(&[
+            Duration::from_secs(u64::MAX),
+            Duration::from_secs(u64::MAX - 2)
+        ]),
+        Duration::from_secs(u64::MAX - 1)
+    );
+}
+#[test]
+fn warmup_for_duration() {
+    let started = Instant::now();
+    warmup_for(Duration::from_millis(100));
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(100));
+    assert!(elapsed < Duration::from_secs(1));
+}
+#[test]
+fn benchmark_bytes_throughput() {
+    let _lock = lock();
+    let mut benchmark = Benchmark::new0();
+    benchmark.set_color(false);
+    benchmark.finish_bytes(Some(1_000), 1_048_576);
+    benchmark.set_elapsed(Duration::from_secs(2));
+    assert_eq!(benchmark.result0().bytes_per_sec, Some(524_288_000));
+    assert!(benchmark.to_string().contains(" 500.00 MiB/s"));
+    benchmark_bytes!(100, 1_024, {
+        black_box(vec![0u8; 1_024]);
+    });
+    assert!(benchmark_result!().bytes_per_sec.is_some());
+}
+#[test]
+fn latency_try_op_finish() {
+    let mut lb = LatencyBenchmark::new();
+    lb.op_start();
+    assert_eq!(lb.try_op_finish(), Ok(()));
+    assert_eq!(lb.len(), 1);
+    assert_eq!(lb.try_op_finish(), Err(LatencyError::NoOpStarted));
+    assert_eq!(lb.len(), 1);
+    assert_eq!(
+        LatencyError::NoOpStarted.to_string(),
+        "no latency op started"
+    );
+}
+
+#[test]
+#[should_panic(expected = "NoOpStarted")]
+fn latency_op_finish_not_started() {
+    LatencyBenchmark::new().op_finish();
+}
+#[test]
+fn latency_histogram() {
+    let _lock = lock();
+    let mut lb = LatencyBenchmark::new();
+    lb.set_color(false);
+    assert!(lb.histogram(4).is_empty());
+    assert_eq!(output(|w| lb.print_histogram_to(w, 4)), "no samples\n");
+    for ms in [10, 12, 15, 22, 25, 38, 48, 50] {
+        lb.push(Duration::from_millis(ms));
+    }
+    let ms = Duration::from_millis;
+    assert_eq!(
+        lb.histogram(4),
+        [
+            (ms(10), ms(20), 3),
+            (ms(20), ms(30), 2),
+            (ms(30), ms(40), 1),
+            (ms(40), ms(50), 2)
+        ]
+    );
+    assert_eq!(lb.histogram(0), [(ms(10), ms(50), 8)]);
+    let out = output(|w| lb.print_histogram_to(w, 4));
+    assert_eq!(out.lines().count(), 4);
+    assert!(out.starts_with("10_000 - 20_000 μs (3) #"));
+}
+fn finished(iterations: u64, errors: u64, elapsed: Duration) -> Benchmark {
+    let mut benchmark = Benchmark::new0();
+    benchmark.set_color(false);
+    benchmark.finish(Some(iterations), Some(errors));
+    benchmark.set_elapsed(elapsed);
+    benchmark
+}
+
+#[test]
+fn print_to_writer() {
+    let _lock = lock();
+    let benchmark = finished(1_000, 0, Duration::from_secs(1));
+    let out = output(|w| benchmark.print_to(w, None, None));
+    assert!(out.contains("Iterations: 1_000, success: 1_000, errors: None"));
+    let staged = staged_with(&[("stage1", 1_000, 0, 500)]);
+    let out = output(|w| staged.print_to(w));
+    assert_eq!(table_rows(&out, &["stage1"])[0][1], "1_000");
+    let perf = perf_with(&[("a", &[10])]);
+    let out = output(|w| perf.print_to(w));
+    assert!(out.starts_with("Iterations: 1\n"));
+    let mut lb = LatencyBenchmark::new();
+    lb.set_color(false);
+    lb.push(Duration::from_micros(10));
+    let out = output(|w| lb.print_to(w));
+    assert_eq!(
+        out,
+        "latency (μs) avg: 10, min: 10, max: 10, p50: 10, p99: 10\n"
+    );
+}
+#[test]
+fn staged_ranked() {
+    let _lock = lock();
+    let staged = staged_with(&[
+        ("a", 1_000, 0, 1_000),
+        ("b", 1_000, 0, 250),
+        ("c", 1_000, 0, 500),
+    ]);
+    let rows = table_rows(&staged.result_table_ranked().to_string(), &["a", "b", "c"]);
+    let ranked: Vec<(&str, &str)> = rows
+        .iter()
+        .map(|row| (row[0].as_str(), row[row.len() - 2].as_str()))
+        .collect();
+    assert_eq!(ranked, [("b", "100.00"), ("c", "50.00"), ("a", "25.00")]);
+    // the default order is kept
+    let rows = table_rows(&staged.result_table().to_string(), &["a", "b", "c"]);
+    assert_eq!(
+        rows.iter().map(|row| &row[0]).collect::<Vec<_>>(),
+        ["a", "b", "c"]
+    );
+}
+#[test]
+fn benchmark_median_speed() {
+    let mut benchmark = Benchmark::new_sampling();
+    for _ in 0..99 {
+        benchmark.sample(Duration::from_micros(1));
+    }
+    benchmark.sample(Duration::from_secs(1));
+    assert_eq!(benchmark.median_speed(), Some(1_000_000));
+    benchmark.finish(Some(100), None);
+    assert_eq!(benchmark.result0().median_speed, Some(1_000_000));
+    assert_eq!(Benchmark::new0().median_speed(), None);
+}
+#[test]
+fn perf_snapshots() {
+    let _lock = lock();
+    let mut perf = Perf::new();
+    perf.set_color(false);
+    perf.start();
+    perf.checkpoint("first");
+    let first = perf.snapshot();
+    perf.reset();
+    perf.start();
+    perf.checkpoint("second");
+    perf.start();
+    perf.checkpoint("second");
+    let second = perf.snapshot();
+    perf.reset();
+    let out = output(|w| first.print_to(w));
+    assert!(out.starts_with("Iterations: 1\n"));
+    assert_eq!(table_rows(&out, &["first", "second"]).len(), 1);
+    assert_eq!(table_rows(&out, &["first"]).len(), 1);
+    let out = output(|w| second.print_to(w));
+    assert!(out.starts_with("Iterations: 2\n"));
+    assert_eq!(table_rows(&out, &["second"]).len(), 1);
+    assert!(!out.contains("first"));
+    Perf::print_snapshot(&first);
+}
+#[test]
+fn duration_units() {
+    assert_eq!(format_duration_scaled(Duration::from_nanos(500)), "500 ns");
+    assert_eq!(
+        format_duration_scaled(Duration::from_nanos(1_500)),
+        "1.500 μs"
+    );
+    assert_eq!(
+        format_duration_scaled(Duration::from_micros(2_500)),
+        "2.500 ms"
+    );
+    assert_eq!(format_duration_scaled(Duration::from_secs(3)), "3.000 s");
+    let _lock = lock();
+    let benchmark = finished(1_000_000, 0, Duration::from_millis(1));
+    assert!(benchmark.to_string().ends_with(" 1 ns per iter"));
+    let benchmark = finished(4, 0, Duration::from_secs(2));
+    assert!(benchmark.to_string().ends_with(" 500.000 ms per iter"));
+}
+#[test]
+fn first_error_index() {
+    let _lock = lock();
+    let mut n = 0;
+    benchmark_check!(100, {
+        let ok = n != 7;
+        n += 1;
+        ok
+    });
+    let result = benchmark_result!();
+    assert_eq!(result.errors, 1);
+    assert_eq!(result.first_error, Some(7));
+    assert!(default_benchmark()
+        .to_string()
+        .contains(", first error at iteration 7"));
+    benchmark_check!(100, { true });
+    assert_eq!(benchmark_result!().first_error, None);
+    assert!(!default_benchmark().to_string().contains("first error"));
+    staged_benchmark_reset!();
+    let mut n = 0;
+    staged_benchmark_check!("check", 100, {
+        let ok = n != 7;
+        n += 1;
+        ok
+    });
+    assert_eq!(staged_benchmark_results!()["check"].first_error, Some(7));
+    staged_benchmark_reset!();
+}
+#[cfg(feature = "async")]
+struct NoopWaker;
+
+#[cfg(feature = "async")]
+impl std::task::Wake for NoopWaker {
+    fn wake(self: std::sync::Arc<Self>) {}
+}
+
+#[cfg(feature = "async")]
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    let mut future = std::pin::pin!(future);
+    let waker = std::task::Waker::from(std::sync::Arc::new(NoopWaker));
+    let mut cx = std::task::Context::from_waker(&waker);
+    loop {
+        if let std::task::Poll::Ready(v) = future.as_mut().poll(&mut cx) {
+            return v;
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_staged() {
+    let _lock = lock();
+    staged_benchmark_reset!();
+    block_on(async {
+        async_staged_benchmark!("async", 1_000, || async {});
+    });
+    assert_eq!(staged_benchmark_results!()["async"].iterations, 1_000);
+    staged_benchmark_reset!();
+}
+#[test]
+fn parallel_iterations() {
+    let result = benchmark_parallel(4, 10_000, || {
+        black_box(1);
+    });
+    assert_eq!(result.iterations, 40_000);
+    assert!(result.speed > 0);
+}
+
+#[test]
+#[should_panic(expected = "the number of threads must be greater than zero")]
+fn parallel_zero_threads() {
+    benchmark_parallel(0, 10, || {});
+}
+#[test]
+fn output_width_override() {
+    let _lock = lock();
+    let title = "--- 20 chars title -";
+    set_output_width(10);
+    assert_eq!(strip_colors(&separator(title).to_string()), title);
+    set_output_width(30);
+    assert_eq!(
+        strip_colors(&separator(title).to_string()),
+        format!("{}{}", title, "-".repeat(10))
+    );
+    reset_output_width();
+}
+#[test]
+fn separator_small_width() {
+    let _lock = lock();
+    set_output_width(1);
+    assert_eq!(
+        strip_colors(&separator("--- Benchmark results ").to_string()),
+        "--- Benchmark results "
+    );
+    let title = "-".repeat(70_000);
+    assert_eq!(strip_colors(&separator(&title).to_string()), title);
+    reset_output_width();
+}
+#[test]
+fn staged_table_columns() {
+    let _lock = lock();
+    let staged = staged_with(&[("a", 1_000, 0, 500)]);
+    let opts = StagedBenchmarkOptions {
+        show_iters: false,
+        show_errors: false,
+        show_secs: false,
+        show_msecs: false,
+        show_speed: true,
+    };
+    let table = staged.result_table_with(&opts, None).to_string();
+    let header: Vec<&str> = table.lines().next().unwrap().split_whitespace().collect();
+    assert_eq!(header, ["stage", "iters/s"]);
+    assert_eq!(table_rows(&table, &["a"]), [["a", "2_000"]]);
+    let opts = StagedBenchmarkOptions {
+        show_speed: false,
+        ..opts
+    };
+    let table = staged.result_table_with(&opts, None).to_string();
+    let header: Vec<&str> = table.lines().next().unwrap().split_whitespace().collect();
+    assert_eq!(header, ["stage"]);
+}
+#[test]
+fn staged_geomean() {
+    let _lock = lock();
+    let staged = staged_with(&[
+        ("a", 1_000, 0, 1_000),
+        ("b", 2_000, 0, 1_000),
+        ("c", 4_000, 0, 1_000),
+    ]);
+    assert!((staged.geomean_speed() - 2_000.0).abs() < 1e-6);
+    assert!((staged.geomean_ratio_vs("a") - 8f64.sqrt()).abs() < 1e-9);
+    assert!(staged.geomean_ratio_vs("none").abs() < f64::EPSILON);
+    let out = output(|w| staged.print_for_to(w, "a"));
+    assert!(out.ends_with("geomean: 2_000 iters/s, 2.828x vs a\n"));
+    // stages with zero speed are skipped
+    let staged = staged_with(&[("a", 1_000, 0, 1_000), ("z", 0, 0, 1_000)]);
+    assert!((staged.geomean_speed() - 1_000.0).abs() < 1e-6);
+}
+#[test]
+fn iterator_benchmark() {
+    let result = benchmark_iter(0..100_000);
+    assert_eq!(result.iterations, 100_000);
+    let _lock = lock();
+    benchmark_iter!((0..10).map(|v| v * 2));
+    assert_eq!(benchmark_result!().iterations, 10);
+}
+#[test]
+fn error_rate() {
+    let result = finished(100, 5, Duration::from_secs(1)).result0();
+    assert!((result.error_rate() - 0.05).abs() < f64::EPSILON);
+    result.assert_error_rate_below(0.1);
+    result.assert_error_rate_below(0.05);
+    let result = finished(0, 0, Duration::from_secs(1)).result0();
+    assert!(result.error_rate().abs() < f64::EPSILON);
+    result.assert_error_rate_below(0.0);
+}
+
+#[test]
+#[should_panic(
+    expected = "benchmark error rate 0.0500 (5 of 100 iterations) exceeds the allowed 0.0100"
+)]
+fn error_rate_exceeded() {
+    finished(100, 5, Duration::from_secs(1))
+        .result0()
+        .assert_error_rate_below(0.01);
+}
+#[test]
+fn staged_markdown() {
+    let _lock = lock();
+    let staged = staged_with(&[("a", 1_000, 0, 500), ("b", 2_000, 0, 250)]);
+    assert_eq!(
+        staged.result_markdown(),
+        "| stage | iters | secs | msecs | iters/s |\n\
+        | --- | ---: | ---: | ---: | ---: |\n\
+        | a | 1_000 | 0.500 | 500.000 | 2_000 |\n\
+        | b | 2_000 | 0.250 | 250.000 | 8_000 |\n"
+    );
+    let staged = staged_with(&[("a", 1_000, 0, 500), ("b", 2_000, 100, 250)]);
+    assert_eq!(
+        staged.result_markdown(),
+        "| stage | iters | succs | errs | err.rate | secs | msecs | iters/s |\n\
+        | --- | ---: | ---: | ---: | ---: | ---: | ---: | ---: |\n\
+        | a | 1_000 | 1_000 |  |  | 0.500 | 500.000 | 2_000 |\n\
+        | b | 2_000 | 1_900 | 100 | 5.00 % | 0.250 | 250.000 | 7_600 |\n"
+    );
+    let mut staged = staged_with(&[("a", 1_000, 0, 500), ("empty", 0, 0, 0)]);
+    staged.mark_timed_out("a");
+    assert_eq!(
+        staged.result_markdown(),
+        "| stage | iters | secs | msecs | iters/s | status |\n\
+        | --- | ---: | ---: | ---: | ---: | ---: |\n\
+        | a | 1_000 | 0.500 | 500.000 | 2_000 | timeout |\n\
+        | empty | 0 | 0.000 | 0.000 | n/a |  |\n"
+    );
+}
+#[test]
+fn benchmark_label() {
+    let _lock = lock();
+    let mut benchmark = Benchmark::new0().with_label("parse_json");
+    benchmark.set_color(false);
+    benchmark.finish(Some(10), None);
+    assert!(benchmark
+        .to_string()
+        .starts_with("--- Benchmark results: parse_json -"));
+    let benchmark = finished(10, 0, Duration::from_secs(1));
+    assert!(benchmark.to_string().starts_with("--- Benchmark results -"));
+    benchmark_labeled!("labeled", 10, {
+        black_box(1);
+    });
+    assert!(strip_colors(&default_benchmark().to_string())
+        .starts_with("--- Benchmark results: labeled -"));
+    benchmark_reset!();
+}
+#[test]
+fn trimmed_speed() {
+    let mut benchmark = Benchmark::new_sampling();
+    for _ in 0..9 {
+        benchmark.sample(Duration::from_millis(1));
+    }
+    benchmark.sample(Duration::from_secs(1));
+    // the untrimmed mean is (9 ms + 1 s) / 10
+    assert_eq!(benchmark.speed_trimmed(0.0), Some(9));
+    assert_eq!(benchmark.speed_trimmed(0.1), Some(1_000));
+    assert_eq!(Benchmark::new0().speed_trimmed(0.1), None);
+}
+
+#[test]
+#[should_panic(expected = "trim fraction must be in 0.0..0.5 range")]
+fn trimmed_speed_invalid_fraction() {
+    Benchmark::new_sampling().speed_trimmed(0.5);
+}
+#[test]
+fn benchmark_returning_value() {
+    let _lock = lock();
+    let sum = benchmark_value!(1_000, { (1..=100_u64).sum::<u64>() });
+    assert_eq!(sum, 5_050);
+    assert_eq!(benchmark_result!().iterations, 1_000);
+}
+#[test]
+fn reset_then_finish() {
+    let mut benchmark = Benchmark::new(1_000);
+    benchmark.finish(Some(2_000), None);
+    assert_eq!(benchmark.result0().iterations, 2_000);
+    benchmark.reset();
+    assert_eq!(benchmark.result0().iterations, 1_000);
+    benchmark.finish(None, None);
+    assert_eq!(benchmark.result0().iterations, 1_000);
+    let mut benchmark = Benchmark::new_dynamic();
+    benchmark.finish(Some(2_000), None);
+    assert_eq!(benchmark.result0().iterations, 2_000);
+    benchmark.reset();
+    assert_eq!(benchmark.result0().iterations, 0);
+    for _ in 0..3 {
+        benchmark.increment();
+    }
+    benchmark.finish(None, None);
+    assert_eq!(benchmark.result0().iterations, 3);
+}
+#[test]
+fn stage_tags() {
+    let mut staged = StagedBenchmark::new();
+    let tags: BTreeMap<String, String> = [("size".to_owned(), "1k".to_owned())].into();
+    staged.start_tagged("tagged", tags.clone());
+    staged.finish("tagged", 10, 0);
+    staged.start("plain");
+    staged.finish("plain", 10, 0);
+    assert_eq!(staged.stage_tags("tagged"), Some(&tags));
+    assert_eq!(staged.stage_tags("plain"), Some(&BTreeMap::new()));
+    assert_eq!(staged.stage_tags("none"), None);
+    assert!(staged
+        .result_csv()
+        .starts_with("stage,size,iterations,errors,elapsed_secs,speed\nplain,,10,0,"));
+}
+#[test]
+fn fast_per_iter() {
+    let _lock = lock();
+    let benchmark = finished(100_000_000, 0, Duration::from_millis(250));
+    let result = benchmark.result0();
+    assert_eq!(result.speed, 400_000_000);
+    assert_eq!(result.per_iter, Duration::from_nanos(2));
+    assert!(benchmark.to_string().ends_with(" 2 ns per iter"));
+}
+#[test]
+fn zero_speed() {
+    let _lock = lock();
+    let benchmark = finished(100, 100, Duration::from_secs(1));
+    assert_eq!(benchmark.result0().speed, 0);
+    assert!(benchmark.to_string().ends_with(" n/a per iter"));
+    let staged = staged_with(&[("b", 1_000, 0, 1_000), ("ref", 100, 100, 1_000)]);
+    let rows = table_rows(&staged.result_table_for("ref").to_string(), &["b", "ref"]);
+    assert_eq!(rows[0].last().unwrap(), "n/a");
+    assert_eq!(rows[1].last().unwrap(), "0");
+}
+#[test]
+fn merge_results() {
+    let results = [
+        finished(1_000, 0, Duration::from_secs(1)).result0(),
+        finished(2_000, 100, Duration::from_secs(1)).result0(),
+        finished(3_000, 0, Duration::from_secs(2)).result0(),
+    ];
+    let merged = BenchmarkResult::merge(&results);
+    assert_eq!(merged.iterations, 6_000);
+    assert_eq!(merged.errors, 100);
+    assert_eq!(merged.elapsed, Duration::from_secs(4));
+    assert_eq!(merged.speed, 1_475);
+    let best = BenchmarkResult::merge_best(&results);
+    assert_eq!(best.iterations, 2_000);
+    assert_eq!(best.speed, 1_900);
+    for empty in [
+        BenchmarkResult::merge(&[]),
+        BenchmarkResult::merge_best(&[]),
+    ] {
+        assert_eq!(empty.iterations, 0);
+        assert_eq!(empty.speed, 0);
+        assert_eq!(empty.elapsed, Duration::ZERO);
+    }
+}
+#[test]
+fn benchmark_with_progress() {
+    let _lock = lock();
+    benchmark_progress!(10_000, 1_000, {
+        black_box(1);
+    });
+    let result = benchmark_result!();
+    assert_eq!(result.iterations, 10_000);
+    assert_eq!(result.errors, 0);
+}
+#[test]
+fn staged_diff_table() {
+    let _lock = lock();
+    let current = staged_with(&[
+        ("a", 2_000, 0, 1_000),
+        ("b", 1_000, 0, 1_000),
+        ("c", 500, 0, 1_000),
+    ]);
+    let base = staged_with(&[
+        ("a", 1_000, 0, 1_000),
+        ("b", 1_000, 0, 1_000),
+        ("d", 800, 0, 1_000),
+    ]);
+    let rows = table_rows(
+        &current.diff_table(&base).to_string(),
+        &["a", "b", "c", "d"],
+    );
+    assert_eq!(
+        rows,
+        [
+            vec!["a", "1_000", "2_000", "+100.00", "%"],
+            vec!["b", "1_000", "1_000"],
+            vec!["c", "-", "500"],
+            vec!["d", "800", "-"],
+        ]
+    );
+}
+#[test]
+fn latency_window() {
+    let mut lb = LatencyBenchmark::windowed(100);
+    for us in 0..1_000 {
+        lb.push(Duration::from_micros(us));
+    }
+    assert_eq!(lb.len(), 100);
+    assert_eq!(lb.min(), Duration::from_micros(900));
+    assert_eq!(lb.max(), Duration::from_micros(999));
+    assert_eq!(lb.avg(), Duration::from_nanos(949_500));
+    assert_eq!(lb.p50(), Duration::from_nanos(949_500));
+}
+#[cfg(all(feature = "x86", target_arch = "x86_64"))]
+#[test]
+fn cpu_cycles() {
+    let started = rdtsc();
+    black_box((0..10_000_u64).map(black_box).sum::<u64>());
+    assert!(rdtsc().wrapping_sub(started) > 0);
+    let _lock = lock();
+    benchmark_cycles!(1_000, {
+        black_box((0..100_u64).map(black_box).sum::<u64>());
+    });
+    assert_eq!(benchmark_result!().iterations, 1_000);
+}
+#[test]
+fn reuse_stage_name() {
+    let _lock = lock();
+    staged_benchmark_reset!();
+    staged_benchmark!("stage", 10, {
+        black_box(1);
+    });
+    assert_eq!(
+        default_staged_benchmark().try_start("stage"),
+        Err(StageError::AlreadyExists("stage".to_owned()))
+    );
+    staged_benchmark_reset!();
+    assert_eq!(default_staged_benchmark().try_start("stage"), Ok(()));
+    staged_benchmark_finish!("stage", 10);
+    assert_eq!(staged_benchmark_results!()["stage"].iterations, 10);
+    staged_benchmark_reset!();
+    benchmark!(10, {
+        black_box(1);
+    });
+    benchmark_reset!();
+    assert_eq!(benchmark_result!().iterations, 0);
+}
+#[test]
+fn default_results() {
+    let _lock = lock();
+    benchmark!(1_000, {
+        black_box(1);
+    });
+    assert_eq!(benchmark_result!().iterations, 1_000);
+    staged_benchmark_reset!();
+    staged_benchmark!("stage", 100, {
+        black_box(1);
+    });
+    let results = staged_benchmark_results!();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results["stage"].iterations, 100);
+    staged_benchmark_reset!();
+}
+#[test]
+fn number_style() {
+    let _lock = lock();
+    assert_eq!(format_number!(1_000_000_u64), "1_000_000");
+    set_number_style(NumberStyle::Plain);
+    assert_eq!(format_number!(1_000_000_u64), "1000000");
+    assert!(finished(1_000_000, 0, Duration::from_secs(1))
+        .to_string()
+        .contains("Iterations: 1000000,"));
+    set_number_style(NumberStyle::Comma);
+    assert_eq!(format_number!(1_000_000_u64), "1,000,000");
+    set_number_locale(Locale::de);
+    assert_eq!(format_number!(1_000_000_u64), "1.000.000");
+    set_number_locale(Locale::en);
+    set_number_style(NumberStyle::Underscore);
+    assert_eq!(format_number!(1_000_000_u64), "1_000_000");
+}
+#[cfg(feature = "alloc-count")]
+#[global_allocator]
+static ALLOC: CountingAllocator = CountingAllocator;
+
+#[cfg(feature = "alloc-count")]
+#[test]
+fn allocations_per_iteration() {
+    CountingAllocator::reset();
+    for _ in 0..100 {
+        black_box(vec![0_u8; 16]);
+        black_box(Vec::<u64>::with_capacity(4));
+    }
+    assert_eq!(CountingAllocator::counters(), (200, 100 * (16 + 32)));
+    let _lock = lock();
+    benchmark_allocs!(100, {
+        black_box(vec![0_u8; 16]);
+    });
+    assert_eq!(benchmark_result!().iterations, 100);
+}
+#[test]
+fn auto_iterations() {
+    let _lock = lock();
+    benchmark_auto!(
+        {
+            black_box(1);
+        },
+        Duration::from_millis(20)
+    );
+    let result = benchmark_result!();
+    assert!(result.elapsed >= Duration::from_millis(20));
+    assert!(result.iterations > 1);
+}
+#[test]
+fn benchmark_elapsed() {
+    let benchmark = Benchmark::new0();
+    std::thread::sleep(Duration::from_millis(1));
+    assert!(benchmark.elapsed() >= Duration::from_millis(1));
+    let mut benchmark = Benchmark::new0();
+    benchmark.finish(Some(1_000), None);
+    benchmark.set_elapsed(Duration::from_secs(2));
+    assert_eq!(benchmark.elapsed(), Duration::from_secs(2));
+    assert_eq!(benchmark.result0().speed, 500);
+}
+#[test]
+fn stage_speedup() {
+    let staged = staged_with(&[("a", 1_000, 0, 1_000), ("b", 2_340, 0, 1_000)]);
+    assert!((staged.speedup("b", "a").unwrap() - 2.34).abs() < 1e-9);
+    assert_eq!(staged.speedup_str("b", "a").unwrap(), "2.34x faster");
+    assert_eq!(staged.speedup_str("a", "b").unwrap(), "2.34x slower");
+    assert_eq!(staged.speedup("b", "none"), None);
+    assert_eq!(staged.speedup("none", "a"), None);
+    assert_eq!(staged.speedup_str("none", "a"), None);
+    let staged = staged_with(&[("a", 1_000, 0, 1_000), ("zero", 0, 0, 1_000)]);
+    assert_eq!(staged.speedup("a", "zero"), None);
+}
+#[test]
+fn latency_drain() {
+    let mut lb = LatencyBenchmark::new();
+    for us in 1..=5 {
+        lb.push(Duration::from_micros(us));
+    }
+    let mut count = 0;
+    let mut total = Duration::ZERO;
+    lb.drain_each(|d| {
+        count += 1;
+        total += d;
+    });
+    assert_eq!(count, 5);
+    assert_eq!(total, Duration::from_micros(15));
+    assert!(lb.is_empty());
+    for us in 1..=3 {
+        lb.push(Duration::from_micros(us));
+    }
+    assert_eq!(
+        lb.take_latencies(),
+        [1, 2, 3].map(Duration::from_micros).to_vec()
+    );
+    assert!(lb.is_empty());
+}
+#[test]
+fn latency_guard() {
+    let mut lb = LatencyBenchmark::new();
+    {
+        let _guard = lb.measure();
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(lb.len(), 1);
+    assert!(lb.max() >= Duration::from_millis(1));
+    std::mem::forget(lb.measure());
+    assert_eq!(lb.len(), 1);
+}
+#[test]
+fn weighted_stage() {
+    let _lock = lock();
+    let mut staged = staged_with(&[("plain", 1_000, 0, 1_000)]);
+    let table = staged.result_table().to_string();
+    assert!(!table.contains("items/s"));
+    staged.start("weighted");
+    staged.finish_weighted("weighted", 1_000, 10, 0);
+    staged
+        .benchmarks
+        .get_mut("weighted")
+        .unwrap()
+        .set_elapsed(Duration::from_secs(2));
+    assert_eq!(staged.results()["weighted"].items_per_sec, Some(5_000));
+    let table = staged.result_table().to_string();
+    assert!(table
+        .lines()
+        .next()
+        .unwrap()
+        .split_whitespace()
+        .eq(["stage", "iters", "secs", "msecs", "iters/s", "items/s"]));
+    let rows = table_rows(&table, &["plain", "weighted"]);
+    assert_eq!(rows[0].last().unwrap(), "1_000");
+    assert_eq!(rows[1].last().unwrap(), "5_000");
+}
+#[test]
+fn iteration_scale_env() {
+    let _lock = lock();
+    // keep the global scale unaffected
+    lazy_static::initialize(&ITERATION_SCALE);
+    std::env::set_var(ITERATION_SCALE_ENV, "0.1");
+    assert!((iteration_scale_from_env() - 0.1).abs() < f64::EPSILON);
+    for invalid in ["abc", "-1", "0", "inf"] {
+        std::env::set_var(ITERATION_SCALE_ENV, invalid);
+        assert!((iteration_scale_from_env() - 1.0).abs() < f64::EPSILON);
+    }
+    std::env::remove_var(ITERATION_SCALE_ENV);
+    assert!((iteration_scale_from_env() - 1.0).abs() < f64::EPSILON);
+}
+#[test]
+fn stage_timeout() {
+    let _lock = lock();
+    staged_benchmark_reset!();
+    default_staged_benchmark().set_stage_timeout(Duration::from_millis(50));
+    staged_benchmark!("slow", 1_000, {
+        std::thread::sleep(Duration::from_millis(10));
+    });
+    let timed_out = {
+        let mut staged = default_staged_benchmark();
+        staged.clear_stage_timeout();
+        staged.set_color(false);
+        let iterations = staged.results()["slow"].iterations;
+        assert!((1..1_000).contains(&iterations));
+        assert!(staged.stage_timed_out("slow"));
+        staged.result_table().to_string()
+    };
+    assert_eq!(
+        table_rows(&timed_out, &["slow"])[0].last().unwrap(),
+        "timeout"
+    );
+    staged_benchmark_reset!();
+    default_staged_benchmark().set_color(true);
+}
+#[test]
+fn result_oneline() {
+    let _lock = lock();
+    let result = finished(1_000_000, 0, Duration::from_millis(1_234)).result0();
+    assert_eq!(
+        result.to_string(),
+        "iters=1_000_000 errs=0 elapsed=1.234s speed=810_372/s"
+    );
+}
+#[test]
+fn staged_expect() {
+    let _lock = lock();
+    staged_benchmark_reset!();
+    staged_benchmark_expect!("equal", 100, 42, { 40 + 2 });
+    staged_benchmark_expect!("diverged", 100, 42, { 41 });
+    let results = staged_benchmark_results!();
+    assert_eq!(results["equal"].errors, 0);
+    assert_eq!(results["diverged"].errors, 100);
+    assert_eq!(results["diverged"].first_error, Some(0));
+    staged_benchmark_reset!();
+}
+#[test]
+fn elements_throughput() {
+    let _lock = lock();
+    let mut benchmark = Benchmark::new0();
+    benchmark.set_color(false);
+    benchmark.finish_elements(Some(1_000), 10);
+    benchmark.set_elapsed(Duration::from_secs(2));
+    let result = benchmark.result0();
+    assert_eq!(result.elements, Some(10));
+    assert_eq!(result.elements_per_sec(), Some(5_000));
+    assert!(benchmark.to_string().ends_with(" 5_000 elements/s"));
+}
+#[test]
+fn raw_benchmark() {
+    let _lock = lock();
+    let mut count = 0;
+    benchmark_raw!(1_000, {
+        count += 1;
+    });
+    assert_eq!(count, 1_000);
+    assert_eq!(benchmark_result!().iterations, 1_000);
+}
+#[test]
+fn sampled_stage() {
+    let _lock = lock();
+    staged_benchmark_reset!();
+    staged_benchmark_sampled!("sampled", 20, {
+        std::thread::sleep(Duration::from_millis(1));
+    });
+    {
+        let staged = default_staged_benchmark();
+        let p50 = staged.stage_percentile("sampled", 50.0).unwrap();
+        assert!(p50 >= Duration::from_millis(1));
+        assert!(p50 < Duration::from_millis(500));
+        assert!(staged.stage_percentile("sampled", 99.0).unwrap() >= p50);
+        assert_eq!(staged.stage_percentile("none", 50.0), None);
+    }
+    staged_benchmark_reset!();
+}
+#[test]
+fn poisoned_default_benchmark() {
+    let _lock = lock();
+    let result = std::thread::spawn(|| {
+        let _benchmark = DEFAULT_BENCHMARK.lock();
+        panic!("benchmark failed");
+    })
+    .join();
+    assert!(result.is_err());
+    assert!(DEFAULT_BENCHMARK.is_poisoned());
+    benchmark!(10, {
+        black_box(1);
+    });
+    assert_eq!(benchmark_result!().iterations, 10);
+    DEFAULT_BENCHMARK.clear_poison();
+}
+#[test]
+fn staged_total() {
+    let _lock = lock();
+    let staged = staged_with(&[
+        ("a", 1_000, 0, 100),
+        ("b", 2_000, 0, 200),
+        ("c", 3_000, 0, 300),
+    ]);
+    assert_eq!(staged.total_elapsed(), Duration::from_millis(600));
+    let rows = table_rows(&output(|w| staged.print_to(w)), &["TOTAL"]);
+    assert_eq!(rows, [["TOTAL", "6_000", "0.600", "600.000", "10_000"]]);
+    // the footer is printed only
+    assert!(table_rows(&staged.result_table().to_string(), &["TOTAL"]).is_empty());
+    let opts = StagedBenchmarkOptions {
+        show_secs: false,
+        ..StagedBenchmarkOptions::default()
+    };
+    assert!(table_rows(
+        &staged.result_table_with(&opts, None).to_string(),
+        &["TOTAL"]
+    )
+    .is_empty());
+    assert!(table_rows(&staged.result_table_ranked().to_string(), &["TOTAL"]).is_empty());
+    let mut staged = staged;
+    staged.mark_timed_out("c");
+    let rows = staged._result_rows(&StagedBenchmarkOptions::default(), Some("a"), true);
+    assert_eq!(rows.header.last(), Some(&"rel"));
+    assert_eq!(rows.total.unwrap().len(), rows.header.len());
+    let rows = table_rows(&output(|w| staged.print_for_to(w, "a")), &["TOTAL"]);
+    assert_eq!(rows, [["TOTAL", "6_000", "0.600", "600.000", "10_000"]]);
+}
+#[test]
+fn repeated_runs() {
+    let result = benchmark_repeated(5, 1_000, || {
+        black_box(1);
+    });
+    assert_eq!(result.speeds.len(), 5);
+    assert!(result.std_dev.is_finite());
+    assert!(result.min <= result.max);
+    let result = RepeatedResult::new(1_000, vec![1_000, 2_000, 3_000, 4_000, 5_000]);
+    assert!((result.mean - 3_000.0).abs() < 1e-9);
+    assert!((result.std_dev - 2_000_000_f64.sqrt()).abs() < 1e-9);
+    assert!((result.cv - result.std_dev / 3_000.0).abs() < 1e-12);
+    assert_eq!((result.min, result.max), (1_000, 5_000));
+}
+#[cfg(feature = "serde")]
+#[test]
+fn latency_jsonl() {
+    let mut lb = LatencyBenchmark::new();
+    for us in [30, 10, 20] {
+        lb.push(Duration::from_micros(us));
+    }
+    let out = output(|w| lb.write_jsonl(w));
+    assert_eq!(out.lines().count(), lb.len());
+    assert_eq!(
+        out,
+        "{\"latency_us\":30}\n{\"latency_us\":10}\n{\"latency_us\":20}\n"
+    );
+}
+#[test]
+fn speed_confidence_interval() {
+    let _lock = lock();
+    let mut benchmark = Benchmark::new_sampling();
+    benchmark.set_color(false);
+    for ms in 1..=5 {
+        benchmark.sample(Duration::from_millis(ms));
+    }
+    benchmark.finish(Some(5), None);
+    benchmark.set_elapsed(Duration::from_millis(15));
+    // the mean iteration duration is 3 ms
+    let mean_speed = 333;
+    let (low, high) = benchmark.result0().speed_ci95().unwrap();
+    assert!(low < mean_speed && mean_speed < high);
+    assert_eq!((low, high), (228, 619));
+    assert!(benchmark
+        .to_string()
+        .contains(" 228 - 619 iters/s (95% CI)"));
+    let mut benchmark = Benchmark::new_sampling();
+    benchmark.sample(Duration::from_millis(1));
+    assert_eq!(benchmark.speed_ci95(), None);
+    assert_eq!(Benchmark::new0().speed_ci95(), None);
+}
+#[test]
+fn staged_compare() {
+    let _lock = lock();
+    staged_benchmark_reset!();
+    staged_benchmark_compare!(100, {
+        "first" => { black_box(1); },
+        "second" => { black_box(2); },
+    });
+    let results = staged_benchmark_results!();
+    assert_eq!(results.keys().collect::<Vec<_>>(), ["first", "second"]);
+    assert!(results.values().all(|r| r.iterations == 100));
+    staged_benchmark_reset!();
+}
+#[test]
+fn setup_excluded() {
+    let _lock = lock();
+    benchmark_with_setup!(5, std::thread::sleep(Duration::from_millis(20)), |(): (
+    )| 1);
+    let result = benchmark_result!();
+    assert_eq!(result.iterations, 5);
+    assert!(result.elapsed < Duration::from_millis(20));
+}
+#[test]
+fn perf_sorted() {
+    let _lock = lock();
+    let perf = perf_with(&[("a", &[10]), ("b", &[30]), ("c", &[20]), ("d", &[20])]);
+    let out = output(|w| perf.print_sorted_to(w));
+    let order: Vec<String> = table_rows(&out, &["a", "b", "c", "d", "TOTAL"])
+        .into_iter()
+        .map(|row| row[0].clone())
+        .collect();
+    assert_eq!(order, ["b", "c", "d", "a", "TOTAL"]);
+    let out = output(|w| perf.print_to(w));
+    let order: Vec<String> = table_rows(&out, &["a", "b", "c", "d", "TOTAL"])
+        .into_iter()
+        .map(|row| row[0].clone())
+        .collect();
+    assert_eq!(order, ["a", "b", "c", "d", "TOTAL"]);
+}
+#[test]
+fn perf_filtered() {
+    let _lock = lock();
+    let perf = perf_with(&[("a", &[10]), ("b", &[20]), ("c", &[30]), ("d", &[40])]);
+    let out = output(|w| perf.print_filtered_to(w, &["b", "d", "missing"]));
+    let rows = table_rows(&out, &["a", "b", "c", "d", "TOTAL"]);
+    assert_eq!(rows.len(), 3);
+    assert_eq!(rows[0][0], "b");
+    assert_eq!(rows[1][0], "d");
+    assert_eq!(rows[2][..4], ["TOTAL", "60", "60", "60"]);
+    let out = output(|w| perf.print_filtered_to(w, &[]));
+    assert_eq!(table_rows(&out, &["a", "b", "c", "d", "TOTAL"]).len(), 5);
+}
+#[test]
+fn perf_percent_of_total() {
+    let _lock = lock();
+    let perf = perf_with(&[("a", &[20, 30]), ("b", &[80, 70])]);
+    let out = output(|w| perf.print_to(w));
+    let pct: Vec<String> = table_rows(&out, &["a", "b", "TOTAL"])
+        .into_iter()
+        .map(|row| row[row.len() - 2].clone())
+        .collect();
+    assert_eq!(pct, ["25.00", "75.00", "100.00"]);
+    let perf = perf_with(&[("a", &[0])]);
+    let out = output(|w| perf.print_to(w));
+    assert_eq!(table_rows(&out, &["a"])[0][6], "0.00");
+}
+#[test]
+fn perf_skipped_checkpoints() {
+    let _lock = lock();
+    let mut perf = Perf::new();
+    perf.set_color(false);
+    for i in 0..4 {
+        perf.start();
+        perf.checkpoint("always");
+        if i % 2 == 0 {
+            perf.checkpoint("sometimes");
+        }
+    }
+    let measurements = perf.measurements();
+    assert_eq!(measurements["always"].count, 4);
+    assert_eq!(measurements["sometimes"].count, 2);
+    assert_eq!(perf.total().count, 2);
+    let out = output(|w| perf.print_to(w));
+    assert_eq!(table_rows(&out, &["always", "sometimes", "TOTAL"]).len(), 3);
+}
+#[test]
+fn perf_nanoseconds() {
+    let _lock = lock();
+    let mut perf = perf_with(&[("a", &[2])]);
+    perf.set_unit(TimeUnit::Ns);
+    let out = output(|w| perf.print_to(w));
+    assert_eq!(
+        table_rows(&out, &["a"])[0][..4],
+        ["a", "2_000", "2_000", "2_000"]
+    );
+    assert!(out.ends_with("(the durations are provided in nanoseconds)\n"));
+    perf.set_unit(TimeUnit::Us);
+    let out = output(|w| perf.print_to(w));
+    assert_eq!(table_rows(&out, &["a"])[0][1], "2");
+    assert!(out.ends_with("(the durations are provided in microseconds)\n"));
+}
+#[test]
+fn diff_tolerance() {
+    let _lock = lock();
+    let mut staged = staged_with(&[("a", 1_000, 0, 1_000), ("b", 1_010, 0, 1_000)]);
+    let rows = table_rows(&staged.result_table_for("a").to_string(), &["b"]);
+    assert_eq!(rows[0][rows[0].len() - 2..], ["+1.00", "%"]);
+    staged.set_diff_tolerance(0.02);
+    let rows = table_rows(&staged.result_table_for("a").to_string(), &["b"]);
+    assert_eq!(rows[0].last().unwrap(), "1_010");
+}
+
+#[test]
+#[should_panic(expected = "diff tolerance must be in 0.0..1.0 range")]
+fn diff_tolerance_invalid() {
+    StagedBenchmark::new().set_diff_tolerance(1.0);
+}
+#[test]
+fn staged_html() {
+    let _lock = lock();
+    let staged = staged_with(&[("a", 1_000, 0, 500), ("b<>", 2_000, 100, 250)]);
+    let html = staged.result_html();
+    assert!(html.starts_with("<!DOCTYPE html>"));
+    assert!(html.contains("<table"));
+    assert_eq!(html.matches("<tr>").count(), 3);
+    assert!(html.contains("<td style=\"padding:2px 8px\">a</td>"));
+    assert!(html.contains("<td style=\"padding:2px 8px\">b&lt;&gt;</td>"));
+    assert!(html.contains("<td style=\"padding:2px 8px;text-align:right;color:red\">100</td>"));
+    let staged = staged_with(&[("empty", 0, 0, 0)]);
+    assert!(staged
+        .result_html()
+        .contains("<td style=\"padding:2px 8px;text-align:right\">n/a</td>"));
+}
+#[cfg(target_os = "linux")]
+#[test]
+fn peak_rss_increase() {
+    assert_eq!(
+        parse_vm_hwm("VmPeak:\t 1 kB\nVmHWM:\t    1234 kB\n"),
+        Some(1_234 * 1_024)
+    );
+    assert_eq!(parse_vm_hwm("VmHWM: garbled"), None);
+    assert_eq!(parse_vm_hwm("VmRSS:\t 1 kB\n"), None);
+    assert_eq!(
+        strip_colors(&rss_summary(Some(1_024), Some(3_072))),
+        " 2_048 bytes peak RSS increase"
+    );
+    assert_eq!(
+        strip_colors(&rss_summary(None, None)),
+        " peak RSS could not be read"
+    );
+    let _lock = lock();
+    let before = peak_rss().unwrap();
+    let buf = black_box(vec![1_u8; 256 * 1_048_576]);
+    let after = peak_rss().unwrap();
+    drop(buf);
+    assert!(after > before);
+    benchmark_rss!(10, {
+        black_box(vec![1_u8; 1_024]);
+    });
+    assert_eq!(benchmark_result!().iterations, 10);
+}
+#[cfg(feature = "serde")]
+#[test]
+fn staged_json_round_trip() {
+    let mut staged = StagedBenchmark::new();
+    for (name, iterations, errors, elapsed) in [("a", 1_000, 0, 500), ("b", 2_000, 100, 250)] {
+        staged.start(name);
+        staged.finish(name, iterations, errors);
+        staged
+            .benchmarks
+            .get_mut(name)
+            .unwrap()
+            .set_elapsed(Duration::from_millis(elapsed));
+    }
+    let loaded = StagedBenchmark::from_json(&staged.results_json()).unwrap();
+    let results = staged.results();
+    let loaded_results = loaded.results();
+    assert_eq!(loaded_results.len(), 2);
+    for (stage, result) in results {
+        let loaded_result = &loaded_results[&stage];
+        assert_eq!(loaded_result.speed, result.speed);
+        assert_eq!(loaded_result.iterations, result.iterations);
+        assert_eq!(loaded_result.errors, result.errors);
+    }
+    assert!(StagedBenchmark::from_json("invalid").is_err());
+}
+/// Run the function with colored output forced (restored after)
+fn with_colors<T>(f: impl FnOnce() -> T) -> T {
+    init_color();
+    colored::control::set_override(true);
+    let result = f();
+    if *COLOR_ENABLED {
+        colored::control::unset_override();
+    } else {
+        colored::control::set_override(false);
+    }
+    result
+}
+
+#[test]
+fn staged_no_color() {
+    let _lock = lock();
+    let mut staged = staged_with(&[("a", 1_000, 0, 500), ("b", 2_000, 100, 250)]);
+    let (colored, plain) = with_colors(|| {
+        staged.set_color(true);
+        let colored = staged.result_table().to_string();
+        staged.set_color(false);
+        (colored, staged.result_table().to_string())
+    });
+    assert!(colored.contains("\x1b["));
+    assert!(!plain.contains("\x1b["));
+    let names = ["a", "b", "TOTAL"];
+    assert_eq!(
+        table_rows(&strip_colors(&colored), &names),
+        table_rows(&plain, &names)
+    );
+}
+#[test]
+fn benchmark_without_iterations() {
+    let _lock = lock();
+    benchmark!({
+        black_box(1);
+    });
+    let result = benchmark_result!();
+    assert!(result.iterations > 0);
+    assert!(result.elapsed >= Duration::from_millis(100));
+}
+#[test]
+#[allow(clippy::manual_is_multiple_of)]
+fn fallible_benchmark() {
+    let _lock = lock();
+    let mut i: u64 = 0;
+    benchmark_try!(300, {
+        i += 1;
+        if i % 3 == 0 {
+            Err(())
+        } else {
+            Ok(i)
+        }
+    });
+    let result = benchmark_result!();
+    assert_eq!(result.errors, 100);
+    assert!((result.error_rate() - 1.0 / 3.0).abs() < 1e-12);
+    assert_eq!(result.first_error, Some(2));
+}
+#[test]
+fn named_benchmarks_print() {
+    let _lock = lock();
+    benchmark_named_start!("first");
+    benchmark_named_start!("second");
+    benchmark_named_finish!("second", 200);
+    benchmark_named_finish!("first", 100);
+    let out = strip_colors(&output(print_named_benchmarks_to));
+    let rows = table_rows(&out, &["first", "second"]);
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0][..2], ["first", "100"]);
+    assert_eq!(rows[1][..2], ["second", "200"]);
+    benchmark_named_start!("empty");
+    benchmark_named_finish!("empty", 0);
+    let out = with_colors(|| output(print_named_benchmarks_to));
+    assert!(out.contains("\x1b["));
+    assert_eq!(
+        table_rows(&strip_colors(&out), &["empty"])[0]
+            .last()
+            .unwrap(),
+        "n/a"
+    );
+    named_benchmarks()
+        .get_mut("empty")
+        .unwrap()
+        .set_color(false);
+    let out = with_colors(|| output(print_named_benchmarks_to));
+    assert!(!out.contains("\x1b["));
+    assert_eq!(table_rows(&out, &["empty"])[0].last().unwrap(), "n/a");
+    named_benchmarks().clear();
+}
+#[test]
+fn speed_above_u32() {
+    let _lock = lock();
+    let benchmark = finished(10_000_000_000, 0, Duration::from_secs(1));
+    assert_eq!(benchmark.result0().speed, 10_000_000_000);
+    assert!(benchmark.to_string().contains(" 10_000_000_000 iters/s"));
+    assert_eq!(rate_per_sec(f64::MAX, 1e-300), u64::MAX);
+    assert_eq!(rate_per_sec(0.0, 0.0), 0);
+}
+#[test]
+fn stage_finish_callback() {
+    let finished = std::sync::Arc::new(Mutex::new(Vec::new()));
+    let mut staged = StagedBenchmark::new();
+    let cb_finished = finished.clone();
+    staged.on_stage_finish(Box::new(move |name, result| {
+        cb_finished
+            .lock()
+            .unwrap()
+            .push((name.to_owned(), result.iterations));
+    }));
+    staged.start("a");
+    staged.finish("a", 10, 0);
+    staged.start("b");
+    staged.finish_current(20, 0);
+    assert_eq!(
+        *finished.lock().unwrap(),
+        [("a".to_owned(), 10), ("b".to_owned(), 20)]
+    );
+    staged.clear_stage_finish_callback();
+    staged.start("c");
+    staged.finish("c", 30, 0);
+    assert_eq!(finished.lock().unwrap().len(), 2);
+}
+#[test]
+#[allow(clippy::manual_is_multiple_of)]
+fn benchmark_check_flag() {
+    let _lock = lock();
+    let mut i: u64 = 0;
+    benchmark!(100, check, {
+        i += 1;
+        i % 2 == 0
+    });
+    let result = benchmark_result!();
+    assert_eq!(result.iterations, 100);
+    assert_eq!(result.errors, 50);
+}
+#[test]
+fn per_stage_warmup() {
+    let _lock = lock();
+    staged_benchmark_reset!();
+    default_staged_benchmark().set_per_stage_warmup(Duration::from_millis(50));
+    let calls = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+    let stage_calls = calls.clone();
+    staged_benchmark!("warm", 100, {
+        stage_calls.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    });
+    default_staged_benchmark().set_per_stage_warmup(Duration::ZERO);
+    assert_eq!(staged_benchmark_results!()["warm"].iterations, 100);
+    assert!(calls.load(std::sync::atomic::Ordering::Relaxed) > 100);
+    staged_benchmark_reset!();
+}
+#[test]
+fn speedup_distribution() {
+    let staged = staged_with(&[
+        ("a", 500, 0, 1_000),
+        ("b", 2_000, 0, 1_000),
+        ("c", 4_000, 0, 1_000),
+        ("ref", 1_000, 0, 1_000),
+    ]);
+    assert_eq!(
+        staged.speedup_distribution("ref"),
+        [
+            ("a".to_owned(), 0.5),
+            ("b".to_owned(), 2.0),
+            ("c".to_owned(), 4.0),
+            ("ref".to_owned(), 1.0)
+        ]
+    );
+    assert_eq!(staged.speedup_summary("ref"), Some((0.5, 1.5, 4.0)));
+    assert!(staged.speedup_distribution("none").is_empty());
+    assert_eq!(staged.speedup_summary("none"), None);
+}
+#[test]
+fn zero_iterations() {
+    let _lock = lock();
+    let benchmark = finished(0, 0, Duration::from_secs(1));
+    assert!(benchmark.to_string().ends_with(NOTHING_MEASURED));
+    let staged = staged_with(&[("empty", 0, 0, 1_000)]);
+    let rows = table_rows(&staged.result_table().to_string(), &["empty"]);
+    assert_eq!(rows[0].last().unwrap(), "n/a");
+    let mut perf = Perf::new();
+    perf.set_color(false);
+    assert_eq!(
+        output(|w| perf.print_to(w)),
+        format!("{}\n", NOTHING_MEASURED)
+    );
+}
+#[test]
+fn box_chars_table() {
+    let _lock = lock();
+    let staged = staged_with(&[("a", 1_000, 0, 500)]);
+    set_table_format(TableStyle::BoxChars);
+    let table = staged.result_table().to_string();
+    set_table_format(TableStyle::Default);
+    assert!(table.contains('│'));
+    assert!(table.starts_with('┌'));
+    assert!(!staged.result_table().to_string().contains('│'));
+}
+#[test]
+fn perf_scoped() {
+    let _lock = lock();
+    let mut perf = Perf::new();
+    perf.set_color(false);
+    perf.iterations = 1;
+    for (parent, name, us) in [
+        (Some("parent"), "c1", 10),
+        (Some("parent"), "c2", 20),
+        (None, "other", 30),
+    ] {
+        perf.checkpoints.push((parent, name));
+        perf.measurements
+            .insert((parent, name), vec![(1, Duration::from_micros(us))]);
+    }
+    let out = output(|w| perf.print_to(w));
+    assert!(out.contains("\n   c1 "));
+    assert!(out.contains("\n   c2 "));
+    let rows = table_rows(&out, &["parent", "c1", "c2", "other", "TOTAL"]);
+    let avg: Vec<(&str, &str)> = rows
+        .iter()
+        .map(|row| (row[0].as_str(), row[3].as_str()))
+        .collect();
+    assert_eq!(
+        avg,
+        [
+            ("parent", "30"),
+            ("c1", "10"),
+            ("c2", "20"),
+            ("other", "30"),
+            ("TOTAL", "60")
+        ]
+    );
+    assert_eq!(perf.total().avg, Duration::from_micros(60));
+    assert_eq!(perf.scoped_measurements().len(), 2);
+}
+#[test]
+fn abort_slow_stage() {
+    let _lock = lock();
+    staged_benchmark_reset!();
+    default_staged_benchmark().set_abort_if_slower_than(2.0);
+    staged_benchmark!("fast", 1_000, {
+        black_box(1);
+    });
+    staged_benchmark!("slow", 1_000, {
+        std::thread::sleep(Duration::from_millis(1));
+    });
+    let table = {
+        let mut staged = default_staged_benchmark();
+        staged.clear_abort_if_slower_than();
+        staged.set_color(false);
+        let results = staged.results();
+        assert_eq!(results["fast"].iterations, 1_000);
+        assert_eq!(results["slow"].iterations, 10);
+        assert!(!staged.stage_aborted("fast"));
+        assert!(staged.stage_aborted("slow"));
+        staged.result_table().to_string()
+    };
+    assert!(
+        table_rows(&table, &["slow"])[0].ends_with(&["aborted".to_owned(), "(slow)".to_owned()])
+    );
+    staged_benchmark_reset!();
+    default_staged_benchmark().set_color(true);
+}
+#[test]
+fn time_closure() {
+    let (value, elapsed) = Benchmark::time(|| {
+        std::thread::sleep(Duration::from_millis(20));
+        5
+    });
+    assert_eq!(value, 5);
+    assert!(elapsed >= Duration::from_millis(20));
+}
+#[test]
+fn time_it_macro() {
+    let (v, d) = time_it!({
+        std::thread::sleep(Duration::from_millis(10));
+        (1..=10_u64).sum::<u64>()
+    });
+    assert_eq!(v, 55);
+    assert!(d >= Duration::from_millis(10));
+}
+#[test]
+fn staged_percentile_columns() {
+    let _lock = lock();
+    let mut staged = staged_with(&[("plain", 1_000, 0, 1_000)]);
+    let table = staged.result_table().to_string();
+    assert!(!table.contains("p50"));
+    staged.start("sampled");
+    staged.finish_sampled(
+        "sampled",
+        3,
+        0,
+        [1, 2, 3].map(Duration::from_millis).to_vec(),
+    );
+    let table = staged.result_table().to_string();
+    assert!(table
+        .lines()
+        .next()
+        .unwrap()
+        .split_whitespace()
+        .eq(["stage", "iters", "secs", "msecs", "iters/s", "p50", "p99"]));
+    let rows = table_rows(&table, &["plain", "sampled"]);
+    assert_eq!(rows[0][rows[0].len() - 2..], ["-", "-"]);
+    assert_eq!(rows[1][rows[1].len() - 4..], ["2.000", "ms", "2.980", "ms"]);
+}
+#[test]
+fn auto_tuning_excluded() {
+    let _lock = lock();
+    benchmark_auto!(
+        {
+            black_box(1);
+        },
+        Duration::from_millis(20)
+    );
+    let result = benchmark_result!();
+    assert!(!result.tuning_elapsed.is_zero());
+    assert!(result.elapsed >= Duration::from_millis(20));
+    #[allow(clippy::cast_precision_loss)]
+    let final_run_speed = rate_per_sec(result.iterations as f64, result.elapsed.as_secs_f64());
+    assert_eq!(result.speed, final_run_speed);
+    let mut benchmark = finished(1_000, 0, Duration::from_secs(1));
+    benchmark.set_tuning_elapsed(Duration::from_secs(3));
+    let result = benchmark.result0();
+    assert_eq!(result.speed, 1_000);
+    assert_eq!(result.tuning_elapsed, Duration::from_secs(3));
+    assert!(benchmark
+        .to_string()
+        .ends_with(" 3.000 secs spent on tuning"));
+}
+#[test]
+fn shared_latency_threads() {
+    let shared = SharedLatencyBenchmark::new();
+    shared.op_start();
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            let shared = shared.clone();
+            s.spawn(move || {
+                assert_eq!(shared.try_op_finish(), Err(LatencyError::NoOpStarted));
+                for us in 0..250 {
+                    shared.push(Duration::from_micros(us));
+                }
+                for _ in 0..10 {
+                    shared.op_start();
+                    shared.op_finish();
+                }
+            });
+        }
+    });
+    assert_eq!(shared.len(), 4 * 260);
+    assert_eq!(shared.try_op_finish(), Ok(()));
+    assert_eq!(shared.merge().len(), 4 * 260 + 1);
+}
+#[test]
+fn exit_on_error_precondition() {
+    let result = finished(100, 1, Duration::from_secs(1)).result0();
+    assert!(result.has_errors());
+    let result = finished(100, 0, Duration::from_secs(1)).result0();
+    assert!(!result.has_errors());
+    // does nothing without errors
+    result.exit_on_error();
+    let staged = staged_with(&[("a", 100, 0, 1_000)]);
+    assert!(!staged.has_errors());
+    staged.exit_on_error();
+}
+#[test]
+fn staged_errors() {
+    let staged = staged_with(&[("clean", 100, 0, 1_000), ("failing", 100, 5, 1_000)]);
+    assert!(staged.has_errors());
+    assert_eq!(staged.total_errors(), 5);
+    let staged = StagedBenchmark::new();
+    assert!(!staged.has_errors());
+    assert_eq!(staged.total_errors(), 0);
+}
+#[test]
+fn fastest_slowest_stages() {
+    let staged = staged_with(&[
+        ("a", 1_000, 0, 1_000),
+        ("b", 2_000, 0, 500),
+        ("c", 4_000, 0, 2_000),
+    ]);
+    assert_eq!(staged.fastest().unwrap().0, "b");
+    assert_eq!(staged.slowest().unwrap().0, "a");
+    assert_eq!(staged.longest_running().unwrap().0, "c");
+    let staged = staged_with(&[("x", 1_000, 0, 1_000), ("y", 1_000, 0, 1_000)]);
+    assert_eq!(staged.fastest().unwrap().0, "x");
+    assert_eq!(staged.slowest().unwrap().0, "x");
+    let staged = StagedBenchmark::new();
+    assert!(staged.fastest().is_none());
+    assert!(staged.slowest().is_none());
+    assert!(staged.longest_running().is_none());
+}
+/// Clock, which advances by a fixed step on each call
+struct SteppingClock {
+    started: Instant,
+    step: Duration,
+    calls: std::sync::atomic::AtomicU32,
+}
+
+impl Clock for SteppingClock {
+    fn now(&self) -> Instant {
+        let calls = self
+            .calls
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        self.started + self.step * calls
+    }
+}
+
+#[test]
+fn custom_clock() {
+    let mut benchmark = Benchmark::with_clock(Box::new(SteppingClock {
+        started: Instant::now(),
+        step: Duration::from_millis(500),
+        calls: <_>::default(),
+    }));
+    assert_eq!(benchmark.elapsed(), Duration::from_millis(500));
+    benchmark.finish(Some(1_000), None);
+    let result = benchmark.result0();
+    assert_eq!(result.elapsed, Duration::from_secs(1));
+    assert_eq!(result.speed, 1_000);
+    benchmark.reset();
+    benchmark.finish(Some(1_000), None);
+    assert_eq!(benchmark.result0().elapsed, Duration::from_millis(500));
+    assert_eq!(benchmark.result0().speed, 2_000);
+}
+#[test]
+fn perf_split() {
+    let _lock = lock();
+    let perf = perf_with(&[("a", &[10, 10, 30, 30])]);
+    let out = output(|w| perf.print_split_to(w, 2));
+    let (first, second) = out.split_once("--- Iterations 3 - 4 ").unwrap();
+    assert!(first.starts_with("--- Iterations 1 - 2 "));
+    assert_eq!(table_rows(first, &["a"])[0][3], "10");
+    assert_eq!(table_rows(second, &["a"])[0][3], "30");
+    let out = output(|w| perf.print_split_to(w, 10));
+    assert!(out.starts_with("--- Iterations 1 - 4 "));
+    assert!(out.ends_with("no iterations after 10\n"));
+}
+#[test]
+fn speed_sparkline() {
+    let mut benchmark = Benchmark::new_sampling();
+    assert_eq!(benchmark.sparkline(), None);
+    for us in 1..=100 {
+        benchmark.sample(Duration::from_micros(us));
+    }
+    let sparkline = benchmark.sparkline().unwrap();
+    assert_eq!(sparkline.chars().count(), SPARKLINE_BUCKETS);
+    assert!(sparkline.starts_with('█'));
+    assert!(sparkline.ends_with('▁'));
+    let mut benchmark = Benchmark::new_sampling();
+    for us in 1..=10 {
+        benchmark.sample(Duration::from_micros(us));
+    }
+    assert_eq!(benchmark.sparkline().unwrap().chars().count(), 10);
+    assert_eq!(Benchmark::new0().sparkline(), None);
+}
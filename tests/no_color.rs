@@ -0,0 +1,36 @@
+use bma_benchmark::{Benchmark, Perf, StagedBenchmark};
+use std::io::Write;
+
+fn output(f: impl FnOnce(&mut dyn Write) -> std::io::Result<()>) -> String {
+    let mut buf = Vec::new();
+    f(&mut buf).unwrap();
+    String::from_utf8(buf).unwrap()
+}
+
+#[test]
+fn no_color_env() {
+    std::env::set_var("NO_COLOR", "1");
+    // colors forced as for a terminal, must be disabled by NO_COLOR before printing
+    colored::control::set_override(true);
+    let mut benchmark = Benchmark::new0();
+    benchmark.finish(Some(1_000), Some(10));
+    let out = output(|w| benchmark.print_to(w, None, None));
+    assert!(out.contains("Iterations: 1_000"));
+    assert!(!out.contains("\x1b["), "{:?}", out);
+    let mut staged = StagedBenchmark::new();
+    staged.start("a");
+    staged.finish("a", 1_000, 10);
+    staged.start("b");
+    staged.finish("b", 1_000, 0);
+    let out = output(|w| staged.print_to(w));
+    assert!(out.contains("TOTAL"));
+    assert!(!out.contains("\x1b["), "{:?}", out);
+    let out = output(|w| staged.print_for_to(w, "a"));
+    assert!(!out.contains("\x1b["), "{:?}", out);
+    let mut perf = Perf::new();
+    perf.start();
+    perf.checkpoint("a");
+    let out = output(|w| perf.print_to(w));
+    assert!(out.contains("TOTAL"));
+    assert!(!out.contains("\x1b["), "{:?}", out);
+}
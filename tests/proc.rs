@@ -0,0 +1,114 @@
+#[macro_use]
+extern crate bma_benchmark;
+
+use bma_benchmark::benchmark_stage;
+use std::hint::black_box;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+static LOCK: Mutex<()> = Mutex::new(());
+
+fn lock() -> MutexGuard<'static, ()> {
+    LOCK.lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+#[benchmark_stage(i = 10, warmup = 1)]
+fn benchmark_warm() {
+    black_box(1);
+}
+
+#[benchmark_stage(i = 10, warmup = 0)]
+fn benchmark_cold() {
+    black_box(1);
+}
+
+#[test]
+fn stage_warmup() {
+    let _lock = lock();
+    staged_benchmark_reset!();
+    let started = Instant::now();
+    benchmark_cold();
+    assert!(started.elapsed() < Duration::from_secs(1));
+    benchmark_warm();
+    assert!(started.elapsed() >= Duration::from_secs(1));
+    let results = staged_benchmark_results!();
+    assert_eq!(results["cold"].iterations, 10);
+    assert_eq!(results["warm"].iterations, 10);
+    staged_benchmark_reset!();
+}
+
+#[benchmark_stage(i = 10, name = "my fancy stage")]
+fn benchmark_fancy() {
+    black_box(1);
+}
+
+#[benchmark_stage(i = 10, name = "tab\tstage")]
+fn benchmark_escaped() {
+    black_box(1);
+}
+
+#[benchmark_stage(i = 10, name = stage1)]
+fn benchmark_ident() {
+    black_box(1);
+}
+
+#[test]
+fn stage_name() {
+    let _lock = lock();
+    staged_benchmark_reset!();
+    benchmark_fancy();
+    benchmark_escaped();
+    benchmark_ident();
+    let results = staged_benchmark_results!();
+    assert_eq!(
+        results.keys().collect::<Vec<_>>(),
+        ["my fancy stage", "stage1", "tab\tstage"]
+    );
+    staged_benchmark_reset!();
+}
+
+const ITERS: u32 = 250;
+
+mod consts {
+    pub const ITERS: u16 = 125;
+    pub const ITERS_USIZE: usize = 50;
+    pub const ITERS_U128: u128 = 25;
+}
+
+#[benchmark_stage(i = ITERS)]
+fn benchmark_const() {
+    black_box(1);
+}
+
+#[benchmark_stage(i = consts::ITERS, check)]
+fn benchmark_const_path() {
+    black_box(true)
+}
+
+#[benchmark_stage(i = consts::ITERS_USIZE)]
+fn benchmark_const_usize() {
+    black_box(1);
+}
+
+#[benchmark_stage(i = consts::ITERS_U128)]
+fn benchmark_const_u128() {
+    black_box(1);
+}
+
+#[test]
+fn stage_const_iterations() {
+    let _lock = lock();
+    staged_benchmark_reset!();
+    benchmark_const();
+    benchmark_const_path();
+    benchmark_const_usize();
+    benchmark_const_u128();
+    let results = staged_benchmark_results!();
+    assert_eq!(results["const"].iterations, 250);
+    assert_eq!(results["const_path"].iterations, 125);
+    assert_eq!(results["const_path"].errors, 0);
+    assert_eq!(results["const_usize"].iterations, 50);
+    assert_eq!(results["const_u128"].iterations, 25);
+    staged_benchmark_reset!();
+}
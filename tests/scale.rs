@@ -0,0 +1,19 @@
+#[macro_use]
+extern crate bma_benchmark;
+
+use std::hint::black_box;
+
+#[test]
+fn iteration_scale() {
+    // must be set before the scale is read for the first time
+    std::env::set_var("BMA_BENCH_SCALE", "0.1");
+    assert_eq!(bma_benchmark::scale_iterations(1_000_000), 100_000);
+    benchmark!(1_000, {
+        black_box(1);
+    });
+    assert_eq!(benchmark_result!().iterations, 100);
+    staged_benchmark!("scaled", 1_000, {
+        black_box(1);
+    });
+    assert_eq!(staged_benchmark_results!()["scaled"].iterations, 100);
+}
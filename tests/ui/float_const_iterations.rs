@@ -0,0 +1,13 @@
+use bma_benchmark::benchmark_stage;
+use std::hint::black_box;
+
+const ITERS: f64 = 1_000.0;
+
+#[benchmark_stage(i = ITERS)]
+fn benchmark_stage1() {
+    black_box(1);
+}
+
+fn main() {
+    benchmark_stage1();
+}
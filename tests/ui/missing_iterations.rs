@@ -0,0 +1,6 @@
+use bma_benchmark::benchmark_stage;
+
+#[benchmark_stage(name = stage1)]
+fn benchmark_stage1() {}
+
+fn main() {}